@@ -0,0 +1,135 @@
+/// Number of points in the table, spaced evenly over the brightness range
+pub const CURVE_POINTS: usize = 17;
+pub const DIMMING_CURVE_LEN: usize = 2 * CURVE_POINTS;
+
+/// Maps perceived brightness to the output level of the leds,
+/// interpolating linearly between the points of a table
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct DimmingCurve {
+    table: [u16; CURVE_POINTS],
+}
+
+/// Lamps without a stored curve keep the square mapping of older firmware
+impl Default for DimmingCurve {
+    fn default() -> Self {
+        Self::square()
+    }
+}
+
+impl DimmingCurve {
+    /// Creates a curve from a table, which may not decrease
+    pub fn from_table(table: [u16; CURVE_POINTS]) -> Option<Self> {
+        table.is_sorted().then_some(Self { table })
+    }
+
+    pub fn table(&self) -> [u16; CURVE_POINTS] {
+        self.table
+    }
+
+    fn from_fn(f: impl Fn(f32) -> f32) -> Self {
+        let table = core::array::from_fn(|i| {
+            let brightness = i as f32 / (CURVE_POINTS - 1) as f32;
+            libm::roundf(f(brightness).clamp(0.0, 1.0) * u16::MAX as f32) as u16
+        });
+        Self { table }
+    }
+
+    /// Output for which the CIE 1931 lightness is linear in brightness
+    pub fn cie1931() -> Self {
+        Self::from_fn(|brightness| {
+            let lightness = brightness * 100.0;
+            if lightness <= 8.0 {
+                lightness / 903.3
+            } else {
+                libm::powf((lightness + 16.0) / 116.0, 3.0)
+            }
+        })
+    }
+
+    pub fn gamma(gamma: f32) -> Self {
+        Self::from_fn(|brightness| libm::powf(brightness, gamma))
+    }
+
+    /// Output is the square of the brightness, as mapped by the first web interface
+    pub fn square() -> Self {
+        Self::gamma(2.0)
+    }
+
+    pub fn linear() -> Self {
+        Self::gamma(1.0)
+    }
+
+    /// Output level for a brightness
+    pub fn apply(&self, brightness: u16) -> u16 {
+        let position = brightness as u32 * (CURVE_POINTS as u32 - 1);
+        let index = (position / u16::MAX as u32) as usize;
+        if index == CURVE_POINTS - 1 {
+            return self.table[index];
+        }
+        let low = self.table[index] as u32;
+        let high = self.table[index + 1] as u32;
+        let fraction = position % u16::MAX as u32;
+        (low + (high - low) * fraction / u16::MAX as u32) as u16
+    }
+
+    /// Brightness for an output level, the inverse of [`DimmingCurve::apply`]
+    pub fn invert(&self, level: u16) -> u16 {
+        let Some(index) = self.table.windows(2).position(|w| level <= w[1]) else {
+            return u16::MAX;
+        };
+        let low = self.table[index] as u32;
+        let high = self.table[index + 1] as u32;
+        let fraction = if high == low {
+            0
+        } else {
+            (level as u32).saturating_sub(low) * u16::MAX as u32 / (high - low)
+        };
+        ((index as u32 * u16::MAX as u32 + fraction) / (CURVE_POINTS as u32 - 1)) as u16
+    }
+
+    pub fn from_bytes(bytes: &[u8; DIMMING_CURVE_LEN]) -> Option<Self> {
+        Self::from_table(core::array::from_fn(|i| {
+            u16::from_le_bytes([bytes[2 * i], bytes[2 * i + 1]])
+        }))
+    }
+
+    pub fn into_bytes(self) -> [u8; DIMMING_CURVE_LEN] {
+        let mut bytes = [0; DIMMING_CURVE_LEN];
+        for (chunk, point) in bytes.chunks_exact_mut(2).zip(self.table) {
+            chunk.copy_from_slice(&point.to_le_bytes());
+        }
+        bytes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn invert_undoes_apply() {
+        for curve in [
+            DimmingCurve::square(),
+            DimmingCurve::cie1931(),
+            DimmingCurve::linear(),
+        ] {
+            // Inverting rounds down, which is off by at most the steepest slope of the curves
+            for brightness in (0..=u16::MAX).step_by(997) {
+                let level = curve.apply(brightness);
+                assert!(curve.apply(curve.invert(level)).abs_diff(level) <= 4);
+            }
+            assert_eq!(curve.apply(0), 0);
+            assert_eq!(curve.apply(u16::MAX), u16::MAX);
+        }
+    }
+
+    #[test]
+    fn table_may_not_decrease() {
+        let mut table = DimmingCurve::linear().table();
+        assert!(DimmingCurve::from_table(table).is_some());
+        table.swap(3, 4);
+        assert!(DimmingCurve::from_table(table).is_none());
+        let curve = DimmingCurve::cie1931();
+        assert_eq!(DimmingCurve::from_bytes(&curve.into_bytes()), Some(curve));
+    }
+}
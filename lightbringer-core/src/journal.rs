@@ -0,0 +1,172 @@
+use alloc::vec::Vec;
+use crc::{Crc, CRC_32_ISO_HDLC};
+
+// A partition is split in two banks, each starting with `[BANK_MAGIC: 4][seq: 4][crc: 4]`.
+// The bank with the highest valid sequence number holds the entries, the other one is spare.
+// An entry is laid out as `[MAGIC: 2][kind: 1][version: 1][len: 2][0xFFFF: 2][payload][crc: 4]`,
+// where the payload is padded to a multiple of 4 bytes and the crc covers header and payload.
+const BANK_MAGIC: [u8; 4] = *b"LJBK";
+pub const BANK_HEADER_LEN: u32 = 12;
+const ENTRY_MAGIC: [u8; 2] = *b"LJ";
+pub const HEADER_LEN: u32 = 8;
+pub const CRC_LEN: u32 = 4;
+pub const WRITE_ALIGN: u32 = 4;
+const SECTOR_SIZE: u32 = 0x1000;
+
+pub const MAX_PAYLOAD_LEN: usize = 512;
+
+pub const CRC: Crc<u32> = Crc::<u32>::new(&CRC_32_ISO_HDLC);
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct EntryHeader {
+    pub kind: u8,
+    pub version: u8,
+    pub len: u32,
+}
+
+impl EntryHeader {
+    pub fn entry_len(&self) -> u32 {
+        HEADER_LEN + self.len.next_multiple_of(WRITE_ALIGN) + CRC_LEN
+    }
+}
+
+/// Number and size of the banks of a partition of `size` bytes
+pub fn bank_layout(size: u32) -> (u32, u32) {
+    let bank_size = size / 2 / SECTOR_SIZE * SECTOR_SIZE;
+    if bank_size == 0 {
+        (1, size / SECTOR_SIZE * SECTOR_SIZE)
+    } else {
+        (2, bank_size)
+    }
+}
+
+pub fn parse_bank_header(raw: &[u8; BANK_HEADER_LEN as usize]) -> Option<u32> {
+    let stored = u32::from_le_bytes(raw[8..12].try_into().unwrap());
+    (raw[..4] == BANK_MAGIC && CRC.checksum(&raw[..8]) == stored)
+        .then(|| u32::from_le_bytes(raw[4..8].try_into().unwrap()))
+}
+
+pub fn encode_bank_header(seq: u32) -> [u8; BANK_HEADER_LEN as usize] {
+    let mut raw = [0; BANK_HEADER_LEN as usize];
+    raw[..4].copy_from_slice(&BANK_MAGIC);
+    raw[4..8].copy_from_slice(&seq.to_le_bytes());
+    let crc = CRC.checksum(&raw[..8]);
+    raw[8..12].copy_from_slice(&crc.to_le_bytes());
+    raw
+}
+
+pub fn parse_header(raw: &[u8; HEADER_LEN as usize]) -> Option<EntryHeader> {
+    if raw[..2] != ENTRY_MAGIC {
+        return None;
+    }
+    let len = u16::from_le_bytes([raw[4], raw[5]]) as u32;
+    if len as usize > MAX_PAYLOAD_LEN {
+        return None;
+    }
+    Some(EntryHeader {
+        kind: raw[2],
+        version: raw[3],
+        len,
+    })
+}
+
+pub fn encode_entry(kind: u8, version: u8, payload: &[u8]) -> Vec<u8> {
+    let header = EntryHeader {
+        kind,
+        version,
+        len: payload.len() as u32,
+    };
+    let mut entry = Vec::with_capacity(header.entry_len() as usize);
+    entry.extend_from_slice(&ENTRY_MAGIC);
+    entry.extend_from_slice(&[kind, version]);
+    entry.extend_from_slice(&(payload.len() as u16).to_le_bytes());
+    entry.extend_from_slice(&[0xFF, 0xFF]);
+    entry.extend_from_slice(payload);
+
+    let crc = CRC.checksum(&entry);
+    entry.resize(header.entry_len() as usize - CRC_LEN as usize, 0xFF);
+    entry.extend_from_slice(&crc.to_le_bytes());
+    entry
+}
+
+/// Reads a string prefixed by its length in one byte, returning it with the remaining bytes
+pub fn read_str(bytes: &[u8]) -> Option<(&str, &[u8])> {
+    let (len, rest) = bytes.split_first()?;
+    let len = *len as usize;
+    if rest.len() < len {
+        return None;
+    }
+    let s = core::str::from_utf8(&rest[..len]).ok()?;
+    Some((s, &rest[len..]))
+}
+
+/// Writes a string prefixed by its length in one byte, the inverse of `read_str`
+pub fn write_str<const N: usize>(bytes: &mut heapless::Vec<u8, N>, s: &str) {
+    bytes.push(s.len() as u8).unwrap();
+    bytes.extend_from_slice(s.as_bytes()).unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn entry_is_aligned_and_checksummed() {
+        let entry = encode_entry(11, 2, &[1, 2, 3, 4, 5]);
+        assert_eq!(entry.len(), 8 + 8 + 4);
+        let header = parse_header(entry[..8].try_into().unwrap()).unwrap();
+        assert_eq!((header.kind, header.version, header.len), (11, 2, 5));
+        assert_eq!(header.entry_len() as usize, entry.len());
+        assert_eq!(&entry[8..13], &[1, 2, 3, 4, 5]);
+        // Padding stays erased, the crc only covers header and payload
+        assert_eq!(&entry[13..16], &[0xFF; 3]);
+        let crc = u32::from_le_bytes(entry[16..].try_into().unwrap());
+        assert_eq!(crc, CRC.checksum(&entry[..13]));
+    }
+
+    #[test]
+    fn header_rejects_erased_and_oversized_entries() {
+        assert!(parse_header(&[0xFF; 8]).is_none());
+        let entry = encode_entry(1, 1, &[0; MAX_PAYLOAD_LEN]);
+        assert!(parse_header(entry[..8].try_into().unwrap()).is_some());
+        let mut raw: [u8; 8] = entry[..8].try_into().unwrap();
+        raw[4..6].copy_from_slice(&(MAX_PAYLOAD_LEN as u16 + 1).to_le_bytes());
+        assert!(parse_header(&raw).is_none());
+    }
+
+    #[test]
+    fn bank_header_round_trip() {
+        let raw = encode_bank_header(7);
+        assert_eq!(parse_bank_header(&raw), Some(7));
+        let mut torn = raw;
+        torn[5] ^= 1;
+        assert_eq!(parse_bank_header(&torn), None);
+        assert_eq!(parse_bank_header(&[0xFF; 12]), None);
+        // An entry is not mistaken for a bank
+        let entry = encode_entry(1, 1, &[0; 4]);
+        assert_eq!(parse_bank_header(entry[..12].try_into().unwrap()), None);
+    }
+
+    #[test]
+    fn banks_need_a_sector_each() {
+        assert_eq!(bank_layout(0x1000), (1, 0x1000));
+        assert_eq!(bank_layout(0x2000), (2, 0x1000));
+        assert_eq!(bank_layout(0x3000), (2, 0x1000));
+        assert_eq!(bank_layout(0x4000), (2, 0x2000));
+        assert_eq!(bank_layout(0), (1, 0));
+    }
+
+    #[test]
+    fn strings_round_trip() {
+        let mut bytes = heapless::Vec::<u8, 16>::new();
+        write_str(&mut bytes, "lamp");
+        write_str(&mut bytes, "");
+        assert_eq!(&bytes[..], b"\x04lamp\x00");
+        let (first, rest) = read_str(&bytes).unwrap();
+        let (second, rest) = read_str(rest).unwrap();
+        assert_eq!((first, second, rest), ("lamp", "", &[][..]));
+        // Truncated and non UTF-8 strings are rejected
+        assert!(read_str(&bytes[..3]).is_none());
+        assert!(read_str(&[1, 0xFF]).is_none());
+    }
+}
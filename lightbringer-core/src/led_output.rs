@@ -0,0 +1,235 @@
+use crate::dimming_curve::DimmingCurve;
+use crate::light_state::LightState;
+use crate::transition::Fade;
+use embassy_time::{Duration, Instant};
+
+/// Length of the linear pieces a hardware fade is split into, to follow the dimming curve
+const SEGMENT_TIME: Duration = Duration::from_millis(200);
+/// Time between duty updates when fading in software
+const STEP_INTERVAL: Duration = Duration::from_millis(20);
+/// Largest value of the 10-bit fade fields of the LEDC peripheral
+const MAX_FADE_FIELD: u32 = 1023;
+
+/// An output channel for one of the led colours
+pub trait LedOutput {
+    /// Sets the duty immediately, stopping a running fade
+    fn set_duty(&mut self, duty: u32);
+
+    /// Starts a linear fade from `from` to `to` over `duration`.
+    /// Returns `false` if the output can not fade by itself, the fade is then stepped in software.
+    fn start_fade(&mut self, _from: u32, _to: u32, _duration: Duration) -> bool {
+        false
+    }
+}
+
+/// Register values of a hardware fade
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct HardwareFade {
+    pub start: u32,
+    pub increase: bool,
+    pub steps: u16,
+    pub cycles_per_step: u16,
+    pub duty_per_step: u16,
+}
+
+impl HardwareFade {
+    /// Plans a fade taking `pwm_cycles` periods, `None` if there is nothing to fade.
+    /// The fade can end up to `duty_per_step - 1` short of `to`, the next segment starts from
+    /// the exact duty again.
+    pub fn plan(from: u32, to: u32, pwm_cycles: u32) -> Option<Self> {
+        let difference = from.abs_diff(to);
+        let max_steps = MAX_FADE_FIELD.min(pwm_cycles);
+        if difference == 0 || max_steps == 0 {
+            return None;
+        }
+        // Rounding the step size up and then taking fewer steps gets as close to `to` as possible
+        let duty_per_step = difference.div_ceil(max_steps);
+        if duty_per_step > MAX_FADE_FIELD {
+            return None;
+        }
+        let steps = difference / duty_per_step;
+        Some(Self {
+            start: from,
+            increase: to > from,
+            steps: steps as u16,
+            cycles_per_step: (pwm_cycles / steps).min(MAX_FADE_FIELD) as u16,
+            duty_per_step: duty_per_step as u16,
+        })
+    }
+}
+
+/// Drives the warm and cold outputs along a fade
+pub struct FadeDriver<O: LedOutput> {
+    warm: O,
+    cold: O,
+    duty_bits: u32,
+}
+
+impl<O: LedOutput> FadeDriver<O> {
+    pub fn new(warm: O, cold: O, duty_bits: u32) -> Self {
+        Self {
+            warm,
+            cold,
+            duty_bits,
+        }
+    }
+
+    /// Duty cycles of the `(warm, cold)` outputs
+    pub fn duties(&self, state: LightState, curve: &DimmingCurve) -> (u32, u32) {
+        let (cold, warm) = state.channels(curve);
+        (
+            (warm as u32) << self.duty_bits >> 16,
+            (cold as u32) << self.duty_bits >> 16,
+        )
+    }
+
+    /// Moves the outputs along the fade, returning when this should be called again or
+    /// `None` when the fade is done
+    pub fn step(&mut self, fade: &Fade, curve: &DimmingCurve, now: Instant) -> Option<Instant> {
+        if fade.is_done(now) {
+            let (warm, cold) = self.duties(fade.target(), curve);
+            self.warm.set_duty(warm);
+            self.cold.set_duty(cold);
+            return None;
+        }
+
+        // Follow the curve with linear fades between points on the transition
+        let segment_end = (now + SEGMENT_TIME).min(fade.end());
+        let (warm_from, cold_from) = self.duties(fade.at(now), curve);
+        let (warm_to, cold_to) = self.duties(fade.at(segment_end), curve);
+        let duration = segment_end - now;
+        if self.warm.start_fade(warm_from, warm_to, duration)
+            && self.cold.start_fade(cold_from, cold_to, duration)
+        {
+            return Some(segment_end);
+        }
+
+        self.warm.set_duty(warm_from);
+        self.cold.set_duty(cold_from);
+        Some(now + STEP_INTERVAL)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec::Vec;
+
+    /// Records the duties it is set to, fading in "hardware" when `hardware` is set
+    #[derive(Default)]
+    struct MockOutput {
+        hardware: bool,
+        duty: u32,
+        fades: Vec<(u32, u32, Duration)>,
+    }
+
+    impl LedOutput for MockOutput {
+        fn set_duty(&mut self, duty: u32) {
+            self.duty = duty;
+        }
+
+        fn start_fade(&mut self, from: u32, to: u32, duration: Duration) -> bool {
+            if self.hardware {
+                self.fades.push((from, to, duration));
+                self.duty = to;
+            }
+            self.hardware
+        }
+    }
+
+    fn driver(hardware: bool) -> FadeDriver<MockOutput> {
+        let output = || MockOutput {
+            hardware,
+            ..Default::default()
+        };
+        FadeDriver::new(output(), output(), 12)
+    }
+
+    fn fade_in() -> Fade {
+        Fade::new(
+            LightState::new(0, 4000),
+            LightState::new(u16::MAX, 4000),
+            Instant::from_millis(0),
+            Duration::from_secs(1),
+        )
+    }
+
+    #[test]
+    fn plan_reaches_target_when_steps_are_capped() {
+        let fade = HardwareFade::plan(0, 1500, 100_000).unwrap();
+        assert_eq!(fade.duty_per_step, 2);
+        assert_eq!(fade.steps, 750);
+        assert_eq!(fade.steps as u32 * fade.duty_per_step as u32, 1500);
+    }
+
+    #[test]
+    fn plan_ends_within_one_step_of_target() {
+        for difference in [1, 7, 1022, 1023, 1024, 1500, 2047, 4095] {
+            for pwm_cycles in [3, 100, 1023, 5000] {
+                let Some(fade) = HardwareFade::plan(100, 100 + difference, pwm_cycles) else {
+                    continue;
+                };
+                let reached = fade.steps as u32 * fade.duty_per_step as u32;
+                assert!(fade.steps as u32 <= MAX_FADE_FIELD.min(pwm_cycles));
+                assert!(reached <= difference);
+                assert!(difference - reached < fade.duty_per_step as u32);
+            }
+        }
+    }
+
+    #[test]
+    fn plan_decreasing_fade() {
+        let fade = HardwareFade::plan(1000, 200, 400).unwrap();
+        assert!(!fade.increase);
+        assert_eq!(fade.start, 1000);
+        assert_eq!(fade.steps, 400);
+        assert_eq!(fade.duty_per_step, 2);
+        assert_eq!(fade.cycles_per_step, 1);
+    }
+
+    #[test]
+    fn plan_nothing_to_fade() {
+        assert_eq!(HardwareFade::plan(5, 5, 100), None);
+        assert_eq!(HardwareFade::plan(0, 100, 0), None);
+        // Would need more than the 10-bit step size
+        assert_eq!(HardwareFade::plan(0, 4095, 2), None);
+    }
+
+    #[test]
+    fn step_in_software() {
+        let mut driver = driver(false);
+        let curve = DimmingCurve::linear();
+        let fade = fade_in();
+
+        let next = driver.step(&fade, &curve, Instant::from_millis(500));
+        assert_eq!(next, Some(Instant::from_millis(500) + STEP_INTERVAL));
+        let (warm, cold) = driver.duties(fade.at(Instant::from_millis(500)), &curve);
+        assert_eq!((driver.warm.duty, driver.cold.duty), (warm, cold));
+        assert!(warm > 0 && cold > 0);
+
+        assert_eq!(driver.step(&fade, &curve, Instant::from_millis(1000)), None);
+        let (warm, cold) = driver.duties(fade.target(), &curve);
+        assert_eq!((driver.warm.duty, driver.cold.duty), (warm, cold));
+    }
+
+    #[test]
+    fn step_in_hardware_segments() {
+        let mut driver = driver(true);
+        let curve = DimmingCurve::linear();
+        let fade = fade_in();
+
+        let now = Instant::from_millis(900);
+        let next = driver.step(&fade, &curve, now);
+        // The last segment is cut off at the end of the fade
+        assert_eq!(next, Some(fade.end()));
+        let (warm_from, _) = driver.duties(fade.at(now), &curve);
+        let (warm_to, _) = driver.duties(fade.target(), &curve);
+        assert_eq!(
+            driver.warm.fades,
+            [(warm_from, warm_to, Duration::from_millis(100))]
+        );
+
+        let next = driver.step(&fade, &curve, Instant::from_millis(0));
+        assert_eq!(next, Some(Instant::from_millis(0) + SEGMENT_TIME));
+    }
+}
@@ -0,0 +1,19 @@
+//! Parts of the lamp firmware that don't touch the hardware, so they can be tested on the host.
+//!
+//! The `.cargo/config.toml` of the firmware builds for the ESP32-C3, so run the tests from
+//! outside the repository: `cargo test --manifest-path <repo>/lightbringer-core/Cargo.toml`
+#![cfg_attr(not(test), no_std)]
+
+extern crate alloc;
+
+pub mod dimming_curve;
+pub mod journal;
+pub mod led_output;
+pub mod light_state;
+pub mod mqtt;
+pub mod ota_data;
+pub mod presets;
+pub mod slaac;
+pub mod transition;
+pub mod url;
+pub mod version;
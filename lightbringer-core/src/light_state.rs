@@ -0,0 +1,103 @@
+use crate::dimming_curve::DimmingCurve;
+
+pub const LIGHT_STATE_LEN: usize = 4;
+/// Length of the `[COLD, WARM, X, Y]` representation used by older clients and records
+pub const CHANNEL_STATE_LEN: usize = 8;
+
+/// Colour temperature of the warm leds in Kelvin
+pub const WARM_TEMPERATURE: u16 = 2700;
+/// Colour temperature of the cold leds in Kelvin
+pub const COLD_TEMPERATURE: u16 = 6500;
+
+#[derive(Copy, Clone, Debug)]
+pub struct LightState {
+    /// Perceived brightness, from off at `0` to full at `u16::MAX`
+    pub brightness: u16,
+    /// Correlated colour temperature in Kelvin
+    pub temperature: u16,
+}
+
+impl Default for LightState {
+    fn default() -> Self {
+        LightState {
+            brightness: 45800,
+            temperature: 3800,
+        }
+    }
+}
+
+fn mired(kelvin: f32) -> f32 {
+    1_000_000.0 / kelvin
+}
+
+impl LightState {
+    pub fn new(brightness: u16, temperature: u16) -> Self {
+        Self {
+            brightness,
+            temperature: temperature.clamp(WARM_TEMPERATURE, COLD_TEMPERATURE),
+        }
+    }
+
+    /// Brightness in `0.0..=1.0`
+    pub fn brightness_fraction(&self) -> f32 {
+        self.brightness as f32 / u16::MAX as f32
+    }
+
+    /// Fraction of the light coming from the cold leds, mixed linearly in mired
+    pub fn cold_fraction(&self) -> f32 {
+        let temperature = self.temperature.clamp(WARM_TEMPERATURE, COLD_TEMPERATURE) as f32;
+        let warm = mired(WARM_TEMPERATURE as f32);
+        let cold = mired(COLD_TEMPERATURE as f32);
+        (warm - mired(temperature)) / (warm - cold)
+    }
+
+    /// PWM values of the `(cold, warm)` channels, with the output level given by the dimming curve
+    pub fn channels(&self, curve: &DimmingCurve) -> (u16, u16) {
+        let level = curve.apply(self.brightness) as f32;
+        let cold = self.cold_fraction();
+        (
+            libm::roundf(cold * level) as u16,
+            libm::roundf((1.0 - cold) * level) as u16,
+        )
+    }
+
+    /// Inverse of [`LightState::channels`]
+    pub fn from_channels(cold: u16, warm: u16, curve: &DimmingCurve) -> Self {
+        let total = cold as u32 + warm as u32;
+        let brightness = curve.invert(total.min(u16::MAX as u32) as u16);
+        let cold_fraction = if total == 0 {
+            0.5
+        } else {
+            cold as f32 / total as f32
+        };
+
+        let warm_mired = mired(WARM_TEMPERATURE as f32);
+        let cold_mired = mired(COLD_TEMPERATURE as f32);
+        // Converting back from mired to Kelvin is the same reciprocal
+        let temperature = mired(warm_mired - cold_fraction * (warm_mired - cold_mired));
+
+        Self::new(brightness, libm::roundf(temperature) as u16)
+    }
+
+    pub fn from_bytes(bytes: &[u8; LIGHT_STATE_LEN]) -> Self {
+        Self::new(
+            u16::from_le_bytes([bytes[0], bytes[1]]),
+            u16::from_le_bytes([bytes[2], bytes[3]]),
+        )
+    }
+
+    pub fn into_bytes(self) -> [u8; LIGHT_STATE_LEN] {
+        let [b0, b1] = self.brightness.to_le_bytes();
+        let [t0, t1] = self.temperature.to_le_bytes();
+        [b0, b1, t0, t1]
+    }
+
+    /// Reads the `[COLD, WARM, X, Y]` representation, the disk position is ignored
+    pub fn from_channel_bytes(bytes: &[u8; CHANNEL_STATE_LEN], curve: &DimmingCurve) -> Self {
+        Self::from_channels(
+            u16::from_le_bytes([bytes[0], bytes[1]]),
+            u16::from_le_bytes([bytes[2], bytes[3]]),
+            curve,
+        )
+    }
+}
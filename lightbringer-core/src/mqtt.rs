@@ -0,0 +1,285 @@
+//! Framing of the MQTT 3.1.1 packets the lamp sends and receives
+
+pub const MAX_PACKET_LEN: usize = 1024;
+
+// Packet types with their flags, see the MQTT 3.1.1 specification
+pub const CONNECT: u8 = 0x10;
+pub const CONNACK: u8 = 0x20;
+pub const PUBLISH: u8 = 0x30;
+pub const PUBACK: u8 = 0x40;
+pub const SUBSCRIBE: u8 = 0x82;
+pub const PINGREQ: u8 = 0xC0;
+pub const DISCONNECT: u8 = 0xE0;
+const RETAIN: u8 = 0x01;
+const QOS_MASK: u8 = 0x06;
+const QOS_1: u8 = 0x02;
+
+const PROTOCOL_LEVEL: u8 = 4;
+const FLAG_CLEAN_SESSION: u8 = 0x02;
+const FLAG_WILL: u8 = 0x04;
+const FLAG_WILL_RETAIN: u8 = 0x20;
+const FLAG_PASSWORD: u8 = 0x40;
+const FLAG_USERNAME: u8 = 0x80;
+
+pub type Packet = heapless::Vec<u8, MAX_PACKET_LEN>;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum FrameError {
+    /// The remaining length takes more than 4 bytes
+    InvalidLength,
+    /// The packet doesn't fit in `MAX_PACKET_LEN`
+    TooLarge,
+}
+
+/// The first byte, the body and the length of a packet
+pub type Frame<'a> = (u8, &'a [u8], usize);
+
+/// Returns the first complete packet in `buffer`, or `None` while the packet is incomplete
+pub fn parse_packet(buffer: &[u8]) -> Result<Option<Frame<'_>>, FrameError> {
+    let Some(&first) = buffer.first() else {
+        return Ok(None);
+    };
+    let mut len = 0;
+    let mut pos = 1;
+    loop {
+        // The remaining length is encoded in at most 4 bytes
+        if pos > 4 {
+            return Err(FrameError::InvalidLength);
+        }
+        let Some(&byte) = buffer.get(pos) else {
+            return Ok(None);
+        };
+        len |= ((byte & 0x7F) as usize) << (7 * (pos - 1));
+        pos += 1;
+        if byte & 0x80 == 0 {
+            break;
+        }
+    }
+    let end = pos.checked_add(len).ok_or(FrameError::InvalidLength)?;
+    Ok(buffer.get(pos..end).map(|body| (first, body, end)))
+}
+
+/// Prefixes a body with the fixed header
+pub fn finish_packet(first: u8, body: &[u8]) -> Result<Packet, FrameError> {
+    let mut packet = Packet::new();
+    push_bytes(&mut packet, &[first])?;
+    let mut len = body.len();
+    loop {
+        let byte = (len % 128) as u8;
+        len /= 128;
+        push_bytes(&mut packet, &[if len > 0 { byte | 0x80 } else { byte }])?;
+        if len == 0 {
+            break;
+        }
+    }
+    push_bytes(&mut packet, body)?;
+    Ok(packet)
+}
+
+fn push_bytes(body: &mut Packet, bytes: &[u8]) -> Result<(), FrameError> {
+    body.extend_from_slice(bytes)
+        .map_err(|_| FrameError::TooLarge)
+}
+
+/// Pushes a string prefixed by its length in two bytes
+fn push_str(body: &mut Packet, s: &str) -> Result<(), FrameError> {
+    push_bytes(body, &(s.len() as u16).to_be_bytes())?;
+    push_bytes(body, s.as_bytes())
+}
+
+/// The fields of a CONNECT packet, which always starts a clean session
+pub struct Connect<'a> {
+    pub client_id: &'a str,
+    pub keep_alive_secs: u16,
+    /// Topic and payload the broker publishes with the retain flag when the connection is lost
+    pub will: (&'a str, &'a str),
+    /// Username and password, when the broker requires them
+    pub credentials: Option<(&'a str, &'a str)>,
+}
+
+impl Connect<'_> {
+    pub fn encode(&self) -> Result<Packet, FrameError> {
+        let mut flags = FLAG_CLEAN_SESSION | FLAG_WILL | FLAG_WILL_RETAIN;
+        if self.credentials.is_some() {
+            flags |= FLAG_USERNAME | FLAG_PASSWORD;
+        }
+
+        let mut body = Packet::new();
+        push_str(&mut body, "MQTT")?;
+        push_bytes(&mut body, &[PROTOCOL_LEVEL, flags])?;
+        push_bytes(&mut body, &self.keep_alive_secs.to_be_bytes())?;
+        push_str(&mut body, self.client_id)?;
+        push_str(&mut body, self.will.0)?;
+        push_str(&mut body, self.will.1)?;
+        if let Some((username, password)) = self.credentials {
+            push_str(&mut body, username)?;
+            push_str(&mut body, password)?;
+        }
+        finish_packet(CONNECT, &body)
+    }
+}
+
+/// The return code of a CONNACK body, `0` when the connection was accepted
+pub fn connack_code(first: u8, body: &[u8]) -> Option<u8> {
+    match (first, body) {
+        (CONNACK, [_, code]) => Some(*code),
+        _ => None,
+    }
+}
+
+pub fn publish_packet(topic: &str, payload: &[u8], retain: bool) -> Result<Packet, FrameError> {
+    let mut body = Packet::new();
+    push_str(&mut body, topic)?;
+    push_bytes(&mut body, payload)?;
+    finish_packet(if retain { PUBLISH | RETAIN } else { PUBLISH }, &body)
+}
+
+pub fn subscribe_packet(topic: &str) -> Result<Packet, FrameError> {
+    let mut body = Packet::new();
+    // Packet identifier, which is unused as we don't wait for the SUBACK
+    push_bytes(&mut body, &1u16.to_be_bytes())?;
+    push_str(&mut body, topic)?;
+    // QoS 0
+    push_bytes(&mut body, &[0])?;
+    finish_packet(SUBSCRIBE, &body)
+}
+
+/// The acknowledgement of a QoS 1 message
+pub fn puback_packet(id: u16) -> [u8; 4] {
+    let [high, low] = id.to_be_bytes();
+    [PUBACK, 2, high, low]
+}
+
+/// A message received from the broker
+#[derive(Debug, Eq, PartialEq)]
+pub struct Publish<'a> {
+    pub topic: &'a [u8],
+    pub payload: &'a [u8],
+    /// Packet identifier to acknowledge, for QoS 1 messages
+    pub ack: Option<u16>,
+}
+
+/// Splits the body of a PUBLISH packet, `None` if it isn't one or it is truncated
+pub fn parse_publish(first: u8, body: &[u8]) -> Option<Publish<'_>> {
+    if first & 0xF0 != PUBLISH {
+        return None;
+    }
+    let topic_len = body
+        .get(..2)
+        .map(|len| u16::from_be_bytes([len[0], len[1]]) as usize)?;
+    let topic = body.get(2..2 + topic_len)?;
+    // Messages with a QoS above 0 have a packet identifier
+    let (id, payload_start) = match first & QOS_MASK {
+        0 => (None, 2 + topic_len),
+        _ => {
+            let id = body.get(2 + topic_len..4 + topic_len)?;
+            (Some(u16::from_be_bytes([id[0], id[1]])), 4 + topic_len)
+        }
+    };
+    Some(Publish {
+        topic,
+        payload: &body[payload_start..],
+        // Only QoS 1 is acknowledged, the subscription is QoS 0 so brokers don't send QoS 2
+        ack: id.filter(|_| first & QOS_MASK == QOS_1),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_complete_packet() {
+        let buffer = [0x30, 0x02, b'o', b'n', 0xD0];
+        let (first, body, len) = parse_packet(&buffer).unwrap().unwrap();
+        assert_eq!((first, body, len), (0x30, &b"on"[..], 4));
+    }
+
+    #[test]
+    fn parse_incomplete_packet() {
+        assert!(parse_packet(&[]).unwrap().is_none());
+        assert!(parse_packet(&[0x30]).unwrap().is_none());
+        assert!(parse_packet(&[0x30, 0x80]).unwrap().is_none());
+        assert!(parse_packet(&[0x30, 0x03, b'o', b'n']).unwrap().is_none());
+    }
+
+    #[test]
+    fn parse_multi_byte_length() {
+        let mut buffer = [0; 203];
+        buffer[..3].copy_from_slice(&[0x30, 0xC8, 0x01]);
+        let (_, body, len) = parse_packet(&buffer).unwrap().unwrap();
+        assert_eq!((body.len(), len), (200, 203));
+    }
+
+    #[test]
+    fn parse_rejects_five_byte_length() {
+        assert_eq!(
+            parse_packet(&[0x30, 0xFF, 0xFF, 0xFF, 0xFF, 0x01]),
+            Err(FrameError::InvalidLength)
+        );
+    }
+
+    #[test]
+    fn finished_packet_parses_back() {
+        let body = [0x5A; 300];
+        let packet = finish_packet(0x30, &body).unwrap();
+        assert_eq!(&packet[..3], &[0x30, 0xAC, 0x02]);
+        let (first, parsed, len) = parse_packet(&packet).unwrap().unwrap();
+        assert_eq!((first, parsed, len), (0x30, &body[..], packet.len()));
+        assert_eq!(
+            finish_packet(0x30, &[0; MAX_PACKET_LEN]),
+            Err(FrameError::TooLarge)
+        );
+    }
+
+    #[test]
+    fn connect_with_will_and_credentials() {
+        let connect = Connect {
+            client_id: "lamp",
+            keep_alive_secs: 60,
+            will: ("a/b", "off"),
+            credentials: Some(("u", "p")),
+        };
+        let packet = connect.encode().unwrap();
+        let (first, body, _) = parse_packet(&packet).unwrap().unwrap();
+        assert_eq!(first, CONNECT);
+        assert_eq!(&body[..10], b"\x00\x04MQTT\x04\xE6\x00\x3C");
+        assert_eq!(
+            &body[10..],
+            b"\x00\x04lamp\x00\x03a/b\x00\x03off\x00\x01u\x00\x01p"
+        );
+    }
+
+    #[test]
+    fn publish_round_trip() {
+        let packet = publish_packet("a/set", b"{}", true).unwrap();
+        let (first, body, _) = parse_packet(&packet).unwrap().unwrap();
+        assert_eq!(first, PUBLISH | RETAIN);
+        let publish = parse_publish(first, body).unwrap();
+        assert_eq!(
+            (publish.topic, publish.payload),
+            (&b"a/set"[..], &b"{}"[..])
+        );
+        assert_eq!(publish.ack, None);
+    }
+
+    #[test]
+    fn qos_1_publish_is_acknowledged() {
+        let body = b"\x00\x01t\x12\x34on";
+        let publish = parse_publish(PUBLISH | QOS_1, body).unwrap();
+        assert_eq!((publish.topic, publish.payload), (&b"t"[..], &b"on"[..]));
+        assert_eq!(publish.ack, Some(0x1234));
+        assert_eq!(puback_packet(0x1234), [PUBACK, 2, 0x12, 0x34]);
+        // QoS 2 isn't acknowledged with a PUBACK, truncated identifiers are rejected
+        assert_eq!(parse_publish(PUBLISH | 0x04, body).unwrap().ack, None);
+        assert!(parse_publish(PUBLISH | QOS_1, b"\x00\x01t\x12").is_none());
+        assert!(parse_publish(SUBSCRIBE, body).is_none());
+    }
+
+    #[test]
+    fn connack_return_code() {
+        assert_eq!(connack_code(CONNACK, &[0, 0]), Some(0));
+        assert_eq!(connack_code(CONNACK, &[0, 5]), Some(5));
+        assert_eq!(connack_code(PUBLISH, &[0, 0]), None);
+    }
+}
@@ -0,0 +1,239 @@
+//! Encoding of the `otadata` partition, which tells the bootloader which OTA slot to boot.
+//! It holds two copies of the ESP-IDF `esp_ota_select_entry_t`, one per sector. The valid entry
+//! with the highest sequence number wins, and boots slot `(seq - 1) % 2`.
+
+use crc::{Algorithm, Crc};
+use serde::Serialize;
+
+pub const ENTRY_LEN: usize = 32;
+const SEQ: usize = 0;
+const STATE: usize = 24;
+const CRC: usize = 28;
+const SLOT_COUNT: u32 = 2;
+
+/// `esp_rom_crc32_le(UINT32_MAX, ..)` over the sequence number
+const SEQ_CRC: Crc<u32> = Crc::<u32>::new(&Algorithm {
+    width: 32,
+    poly: 0x04c11db7,
+    init: 0x00000000,
+    refin: true,
+    refout: true,
+    xorout: 0xffffffff,
+    check: 0xd202d277,
+    residue: 0xdebb20e3,
+});
+
+/// `esp_ota_img_states_t` of an entry
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SlotState {
+    New,
+    PendingVerify,
+    Valid,
+    Invalid,
+    Aborted,
+    Undefined,
+}
+
+impl SlotState {
+    fn from_raw(raw: u32) -> Self {
+        match raw {
+            0 => SlotState::New,
+            1 => SlotState::PendingVerify,
+            2 => SlotState::Valid,
+            3 => SlotState::Invalid,
+            4 => SlotState::Aborted,
+            _ => SlotState::Undefined,
+        }
+    }
+
+    fn into_raw(self) -> u32 {
+        match self {
+            SlotState::New => 0,
+            SlotState::PendingVerify => 1,
+            SlotState::Valid => 2,
+            SlotState::Invalid => 3,
+            SlotState::Aborted => 4,
+            SlotState::Undefined => u32::MAX,
+        }
+    }
+
+    /// Whether the image still has to prove that it works. Images activated by tools without
+    /// rollback support are `Undefined` and considered working.
+    pub fn is_pending(self) -> bool {
+        matches!(self, SlotState::New | SlotState::PendingVerify)
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct OtaEntry {
+    /// Sector of `otadata` the entry is stored in
+    pub sector: u32,
+    seq: u32,
+    raw: [u8; ENTRY_LEN],
+    pub state: SlotState,
+}
+
+impl OtaEntry {
+    /// `None` when the sector is erased or its sequence number doesn't match the crc
+    pub fn parse(sector: u32, raw: [u8; ENTRY_LEN]) -> Option<Self> {
+        let word = |pos: usize| u32::from_le_bytes(raw[pos..pos + 4].try_into().unwrap());
+        let seq = word(SEQ);
+        let valid_crc = word(CRC) == SEQ_CRC.checksum(&raw[SEQ..SEQ + 4]);
+        (seq != u32::MAX && valid_crc).then_some(Self {
+            sector,
+            seq,
+            raw,
+            state: SlotState::from_raw(word(STATE)),
+        })
+    }
+
+    /// The bootloader skips entries that were marked as broken
+    pub fn is_usable(&self) -> bool {
+        !matches!(self.state, SlotState::Invalid | SlotState::Aborted)
+    }
+
+    /// Increases with every update, so it identifies the installed image
+    pub fn seq(&self) -> u32 {
+        self.seq
+    }
+
+    /// The OTA slot this entry boots, 0 for `ota_0` and 1 for `ota_1`
+    pub fn slot(&self) -> u8 {
+        (self.seq.wrapping_sub(1) % SLOT_COUNT) as u8
+    }
+
+    /// The entry with another state, to be written back to its sector
+    pub fn with_state(&self, state: SlotState) -> [u8; ENTRY_LEN] {
+        let mut raw = self.raw;
+        raw[STATE..STATE + 4].copy_from_slice(&state.into_raw().to_le_bytes());
+        raw
+    }
+}
+
+/// The entry the bootloader follows, `None` when it boots the first app partition
+pub fn active_entry(entries: [Option<OtaEntry>; 2]) -> Option<OtaEntry> {
+    let [a, b] = entries;
+    a.into_iter()
+        .chain(b)
+        .filter(OtaEntry::is_usable)
+        .max_by_key(|entry| entry.seq)
+}
+
+/// State of each OTA slot according to its newest entry, `None` when no entry points to it
+pub fn slot_states(entries: [Option<OtaEntry>; 2]) -> [Option<SlotState>; 2] {
+    let mut states = [None; 2];
+    let mut entries = entries;
+    entries.sort_unstable_by_key(|entry| entry.map(|entry| entry.seq));
+    for entry in entries.into_iter().flatten() {
+        states[entry.slot() as usize] = Some(entry.state);
+    }
+    states
+}
+
+/// An entry that makes the bootloader boot `slot` next, and the sector to write it to. That is
+/// the sector without the newest entry, so a power loss while writing keeps the current one.
+pub fn activation_entry(
+    entries: [Option<OtaEntry>; 2],
+    slot: u8,
+    state: SlotState,
+) -> (u32, [u8; ENTRY_LEN]) {
+    let newest = entries.into_iter().flatten().max_by_key(|entry| entry.seq);
+    let mut seq = newest.map_or(0, |entry| entry.seq) + 1;
+    if (seq - 1) % SLOT_COUNT != slot as u32 {
+        seq += 1;
+    }
+    let sector = newest.map_or(0, |entry| (entry.sector + 1) % 2);
+
+    let mut raw = [0xFF; ENTRY_LEN];
+    raw[SEQ..SEQ + 4].copy_from_slice(&seq.to_le_bytes());
+    raw[STATE..STATE + 4].copy_from_slice(&state.into_raw().to_le_bytes());
+    let crc = SEQ_CRC.checksum(&raw[SEQ..SEQ + 4]);
+    raw[CRC..CRC + 4].copy_from_slice(&crc.to_le_bytes());
+    (sector, raw)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(sector: u32, seq: u32, state: SlotState) -> OtaEntry {
+        let mut raw = [0xFF; ENTRY_LEN];
+        raw[SEQ..SEQ + 4].copy_from_slice(&seq.to_le_bytes());
+        raw[STATE..STATE + 4].copy_from_slice(&state.into_raw().to_le_bytes());
+        let crc = SEQ_CRC.checksum(&raw[SEQ..SEQ + 4]);
+        raw[CRC..CRC + 4].copy_from_slice(&crc.to_le_bytes());
+        OtaEntry::parse(sector, raw).unwrap()
+    }
+
+    #[test]
+    fn sequence_crc_matches_esp_idf() {
+        assert_eq!(SEQ_CRC.checksum(&1u32.to_le_bytes()), 0x4743989a);
+        assert_eq!(SEQ_CRC.checksum(&2u32.to_le_bytes()), 0x55f63774);
+    }
+
+    #[test]
+    fn parse_rejects_erased_and_corrupt_entries() {
+        assert!(OtaEntry::parse(0, [0xFF; ENTRY_LEN]).is_none());
+        let mut raw = entry(0, 1, SlotState::Valid).with_state(SlotState::Valid);
+        raw[CRC] ^= 1;
+        assert!(OtaEntry::parse(0, raw).is_none());
+    }
+
+    #[test]
+    fn slot_follows_sequence_number() {
+        assert_eq!(entry(0, 1, SlotState::Valid).slot(), 0);
+        assert_eq!(entry(0, 2, SlotState::Valid).slot(), 1);
+        assert_eq!(entry(0, 3, SlotState::Valid).slot(), 0);
+    }
+
+    #[test]
+    fn active_skips_broken_entries() {
+        let old = entry(0, 1, SlotState::Valid);
+        let new = entry(1, 2, SlotState::PendingVerify);
+        assert_eq!(active_entry([Some(old), Some(new)]).unwrap().seq(), 2);
+        let invalid = entry(1, 2, SlotState::Invalid);
+        assert_eq!(active_entry([Some(old), Some(invalid)]).unwrap().seq(), 1);
+        assert!(active_entry([None, None]).is_none());
+    }
+
+    #[test]
+    fn state_change_keeps_sequence_number() {
+        let raw = entry(1, 5, SlotState::New).with_state(SlotState::Valid);
+        let changed = OtaEntry::parse(1, raw).unwrap();
+        assert_eq!((changed.seq(), changed.state), (5, SlotState::Valid));
+    }
+
+    #[test]
+    fn slot_states_follow_newest_entry() {
+        let old = entry(0, 1, SlotState::Valid);
+        let new = entry(1, 2, SlotState::PendingVerify);
+        assert_eq!(
+            slot_states([Some(new), Some(old)]),
+            [Some(SlotState::Valid), Some(SlotState::PendingVerify)]
+        );
+        // Both entries boot slot 0 after an update was written twice to the same slot
+        let newer = entry(1, 3, SlotState::New);
+        assert_eq!(
+            slot_states([Some(old), Some(newer)]),
+            [Some(SlotState::New), None]
+        );
+        assert_eq!(slot_states([None, None]), [None, None]);
+    }
+
+    #[test]
+    fn activation_writes_other_sector() {
+        let (sector, raw) = activation_entry([None, None], 1, SlotState::New);
+        let activated = OtaEntry::parse(sector, raw).unwrap();
+        assert_eq!((sector, activated.seq(), activated.slot()), (0, 2, 1));
+        assert_eq!(activated.state, SlotState::New);
+
+        // Rolling back from slot 1 activates slot 0 with a newer entry in sector 0
+        let old = entry(0, 1, SlotState::Valid);
+        let new = entry(1, 2, SlotState::Invalid);
+        let (sector, raw) = activation_entry([Some(old), Some(new)], 0, SlotState::Valid);
+        let activated = OtaEntry::parse(sector, raw).unwrap();
+        assert_eq!((sector, activated.seq(), activated.slot()), (0, 3, 0));
+        assert_eq!(active_entry([Some(activated), Some(new)]).unwrap().seq(), 3);
+    }
+}
@@ -0,0 +1,164 @@
+//! Named light states, stored as a single journal record
+
+use crate::journal::{read_str, write_str};
+use crate::light_state::{LightState, LIGHT_STATE_LEN};
+use heapless::String;
+
+pub const MAX_PRESETS: usize = 8;
+pub const MAX_PRESET_NAME_LEN: usize = 32;
+const PRESET_LEN: usize = 1 + MAX_PRESET_NAME_LEN + LIGHT_STATE_LEN;
+pub const PRESETS_LEN: usize = 1 + MAX_PRESETS * PRESET_LEN;
+
+pub type PresetName = String<MAX_PRESET_NAME_LEN>;
+
+/// A named light state that can be recalled
+#[derive(Clone, Debug)]
+pub struct Preset {
+    pub name: PresetName,
+    pub state: LightState,
+}
+
+/// The presets in the order they were created
+#[derive(Clone, Debug, Default)]
+pub struct Presets(heapless::Vec<Preset, MAX_PRESETS>);
+
+impl Presets {
+    /// Reads `[COUNT, (NAME_LEN, NAME.., LIGHT_STATE)..]`
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let (count, mut rest) = bytes.split_first()?;
+        let mut presets = heapless::Vec::new();
+        for _ in 0..*count {
+            let (name, bytes) = read_str(rest)?;
+            let state = bytes.get(..LIGHT_STATE_LEN)?.try_into().ok()?;
+            let preset = Preset {
+                name: name.try_into().ok()?,
+                state: LightState::from_bytes(state),
+            };
+            presets.push(preset).ok()?;
+            rest = &bytes[LIGHT_STATE_LEN..];
+        }
+        Some(Self(presets))
+    }
+
+    pub fn into_bytes(self) -> heapless::Vec<u8, PRESETS_LEN> {
+        let mut bytes = heapless::Vec::new();
+        bytes.push(self.0.len() as u8).unwrap();
+        for preset in &self.0 {
+            write_str(&mut bytes, &preset.name);
+            bytes.extend_from_slice(&preset.state.into_bytes()).unwrap();
+        }
+        bytes
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Preset> {
+        self.0.iter()
+    }
+
+    pub fn get(&self, name: &str) -> Option<LightState> {
+        self.0
+            .iter()
+            .find(|preset| preset.name == name)
+            .map(|preset| preset.state)
+    }
+
+    /// Adds a preset, replacing a preset with the same name. Fails when the list is full.
+    pub fn add(&mut self, preset: Preset) -> Result<(), Preset> {
+        match self.0.iter_mut().find(|p| p.name == preset.name) {
+            Some(existing) => {
+                *existing = preset;
+                Ok(())
+            }
+            None => self.0.push(preset),
+        }
+    }
+
+    /// Removes the preset with the given name, returning whether it existed
+    pub fn remove(&mut self, name: &str) -> bool {
+        let len = self.0.len();
+        self.0.retain(|p| p.name != name);
+        self.0.len() != len
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn preset(name: &str, brightness: u16) -> Preset {
+        Preset {
+            name: name.try_into().unwrap(),
+            state: LightState::new(brightness, 3000),
+        }
+    }
+
+    fn names(presets: &Presets) -> alloc::vec::Vec<&str> {
+        presets.iter().map(|preset| preset.name.as_str()).collect()
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut presets = Presets::default();
+        presets.add(preset("reading", 60000)).unwrap();
+        presets.add(preset("night", 500)).unwrap();
+        let bytes = presets.into_bytes();
+        assert_eq!(&bytes[..9], b"\x02\x07reading");
+
+        let presets = Presets::from_bytes(&bytes).unwrap();
+        assert_eq!(names(&presets), ["reading", "night"]);
+        let night = presets.get("night").unwrap();
+        assert_eq!((night.brightness, night.temperature), (500, 3000));
+        assert!(presets.get("evening").is_none());
+    }
+
+    #[test]
+    fn largest_list_fits_record() {
+        let mut presets = Presets::default();
+        for i in 0..MAX_PRESETS {
+            let mut name = PresetName::new();
+            while name.len() < MAX_PRESET_NAME_LEN {
+                name.push(char::from(b'a' + i as u8)).unwrap();
+            }
+            presets
+                .add(Preset {
+                    name,
+                    ..preset("", 0)
+                })
+                .unwrap();
+        }
+        assert!(presets.add(preset("evening", 0)).is_err());
+        let bytes = presets.into_bytes();
+        assert_eq!(bytes.len(), PRESETS_LEN);
+        assert!(bytes.len() <= crate::journal::MAX_PAYLOAD_LEN);
+    }
+
+    #[test]
+    fn add_replaces_same_name() {
+        let mut presets = Presets::default();
+        presets.add(preset("reading", 60000)).unwrap();
+        presets.add(preset("night", 500)).unwrap();
+        presets.add(preset("reading", 40000)).unwrap();
+        assert_eq!(names(&presets), ["reading", "night"]);
+        assert_eq!(presets.get("reading").unwrap().brightness, 40000);
+    }
+
+    #[test]
+    fn remove_keeps_order() {
+        let mut presets = Presets::default();
+        for name in ["reading", "evening", "night"] {
+            presets.add(preset(name, 1000)).unwrap();
+        }
+        assert!(presets.remove("evening"));
+        assert!(!presets.remove("evening"));
+        assert_eq!(names(&presets), ["reading", "night"]);
+    }
+
+    #[test]
+    fn rejects_truncated_records() {
+        let mut presets = Presets::default();
+        presets.add(preset("night", 500)).unwrap();
+        let bytes = presets.into_bytes();
+        assert!(Presets::from_bytes(&bytes[..bytes.len() - 1]).is_none());
+        assert!(Presets::from_bytes(&[]).is_none());
+        assert_eq!(Presets::from_bytes(&[0]).unwrap().iter().count(), 0);
+    }
+}
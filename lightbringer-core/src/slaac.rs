@@ -0,0 +1,298 @@
+//! Stateless address autoconfiguration (RFC 4862): the lamp asks routers for their prefixes and
+//! combines an announced `/64` prefix with an interface identifier derived from its MAC address.
+//! Packets are handled with their IPv6 header, as raw sockets send and receive them.
+
+pub type Ipv6Octets = [u8; 16];
+
+pub const IPV6_HEADER_LEN: usize = 40;
+const NEXT_HEADER_ICMPV6: u8 = 58;
+/// Neighbor discovery packets from other links are dropped, as routers can't forward them
+const HOP_LIMIT: u8 = 255;
+
+const TYPE_ROUTER_SOLICITATION: u8 = 133;
+const TYPE_ROUTER_ADVERTISEMENT: u8 = 134;
+const OPTION_SOURCE_LINK_LAYER_ADDRESS: u8 = 1;
+const OPTION_PREFIX_INFORMATION: u8 = 3;
+/// The prefix may be used to configure an address
+const FLAG_AUTONOMOUS: u8 = 0x40;
+/// Length of the prefixes an address can be formed from with a 64 bit interface identifier
+pub const PREFIX_LEN: u8 = 64;
+/// Lifetime of addresses that don't expire
+pub const INFINITE_LIFETIME: u32 = u32::MAX;
+
+const SOLICITATION_LEN: usize = IPV6_HEADER_LEN + 16;
+/// `ff02::2`
+pub const ALL_ROUTERS: Ipv6Octets = [0xff, 2, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 2];
+
+/// A prefix a router allows addresses to be formed from
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Prefix {
+    pub prefix: Ipv6Octets,
+    /// Seconds the address stays valid
+    pub valid_lifetime: u32,
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct RouterAdvertisement {
+    /// Link local address of the router
+    pub router: Ipv6Octets,
+    /// Seconds the router may be used as default router, `0` if it isn't one
+    pub router_lifetime: u16,
+    /// The first prefix an address can be formed from
+    pub prefix: Option<Prefix>,
+}
+
+/// The modified EUI-64 interface identifier of a MAC address
+pub fn interface_id(mac: [u8; 6]) -> [u8; 8] {
+    let [a, b, c, d, e, f] = mac;
+    [a ^ 0x02, b, c, 0xff, 0xfe, d, e, f]
+}
+
+/// The address formed from a prefix and a MAC address
+pub fn address(prefix: &Ipv6Octets, mac: [u8; 6]) -> Ipv6Octets {
+    let mut address = *prefix;
+    address[8..].copy_from_slice(&interface_id(mac));
+    address
+}
+
+/// The `fe80::/64` address of a MAC address
+pub fn link_local_address(mac: [u8; 6]) -> Ipv6Octets {
+    let mut prefix = [0; 16];
+    prefix[..2].copy_from_slice(&[0xfe, 0x80]);
+    address(&prefix, mac)
+}
+
+fn is_link_local(address: &Ipv6Octets) -> bool {
+    address[0] == 0xfe && address[1] & 0xc0 == 0x80
+}
+
+fn is_multicast(address: &Ipv6Octets) -> bool {
+    address[0] == 0xff
+}
+
+/// The ICMPv6 checksum, which covers a pseudo header with the addresses
+pub fn checksum(source: &Ipv6Octets, destination: &Ipv6Octets, icmp: &[u8]) -> u16 {
+    let mut sum: u32 = 0;
+    let mut add = |bytes: &[u8]| {
+        for chunk in bytes.chunks(2) {
+            let high = chunk[0] as u32;
+            let low = chunk.get(1).copied().unwrap_or(0) as u32;
+            sum += high << 8 | low;
+        }
+    };
+    add(source);
+    add(destination);
+    add(&(icmp.len() as u32).to_be_bytes());
+    add(&[0, 0, 0, NEXT_HEADER_ICMPV6]);
+    add(icmp);
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+fn write_ipv6_header(packet: &mut [u8], source: &Ipv6Octets, destination: &Ipv6Octets) {
+    let payload_len = (packet.len() - IPV6_HEADER_LEN) as u16;
+    packet[0] = 0x60;
+    packet[4..6].copy_from_slice(&payload_len.to_be_bytes());
+    packet[6] = NEXT_HEADER_ICMPV6;
+    packet[7] = HOP_LIMIT;
+    packet[8..24].copy_from_slice(source);
+    packet[24..40].copy_from_slice(destination);
+}
+
+/// A router solicitation to all routers, sent from the link local address of `mac`
+pub fn router_solicitation(mac: [u8; 6]) -> [u8; SOLICITATION_LEN] {
+    let source = link_local_address(mac);
+    let mut packet = [0; SOLICITATION_LEN];
+    write_ipv6_header(&mut packet, &source, &ALL_ROUTERS);
+
+    let icmp = &mut packet[IPV6_HEADER_LEN..];
+    icmp[0] = TYPE_ROUTER_SOLICITATION;
+    // Lets the routers answer without resolving our address first
+    icmp[8] = OPTION_SOURCE_LINK_LAYER_ADDRESS;
+    icmp[9] = 1;
+    icmp[10..16].copy_from_slice(&mac);
+    let checksum = checksum(&source, &ALL_ROUTERS, icmp);
+    icmp[2..4].copy_from_slice(&checksum.to_be_bytes());
+    packet
+}
+
+/// Parses a router advertisement with its IPv6 header, `None` for any other or invalid packet
+pub fn parse_router_advertisement(packet: &[u8]) -> Option<RouterAdvertisement> {
+    if packet.len() < IPV6_HEADER_LEN || packet[0] >> 4 != 6 {
+        return None;
+    }
+    let payload_len = u16::from_be_bytes([packet[4], packet[5]]) as usize;
+    let source: Ipv6Octets = packet[8..24].try_into().unwrap();
+    let destination: Ipv6Octets = packet[24..40].try_into().unwrap();
+    let icmp = packet.get(IPV6_HEADER_LEN..IPV6_HEADER_LEN + payload_len)?;
+    if packet[6] != NEXT_HEADER_ICMPV6 || packet[7] != HOP_LIMIT || !is_link_local(&source) {
+        return None;
+    }
+    if icmp.len() < 16 || icmp[0] != TYPE_ROUTER_ADVERTISEMENT || icmp[1] != 0 {
+        return None;
+    }
+    // A correct checksum sums to zero
+    if checksum(&source, &destination, icmp) != 0 {
+        return None;
+    }
+
+    let mut advertisement = RouterAdvertisement {
+        router: source,
+        router_lifetime: u16::from_be_bytes([icmp[6], icmp[7]]),
+        prefix: None,
+    };
+    let mut options = &icmp[16..];
+    while !options.is_empty() {
+        let len = *options.get(1)? as usize * 8;
+        if len == 0 || len > options.len() {
+            return None;
+        }
+        let (option, rest) = options.split_at(len);
+        if option[0] == OPTION_PREFIX_INFORMATION && advertisement.prefix.is_none() {
+            advertisement.prefix = parse_prefix(option);
+        }
+        options = rest;
+    }
+    Some(advertisement)
+}
+
+/// A prefix information option, if an address can be formed from it
+fn parse_prefix(option: &[u8]) -> Option<Prefix> {
+    if option.len() != 32 || option[2] != PREFIX_LEN || option[3] & FLAG_AUTONOMOUS == 0 {
+        return None;
+    }
+    let valid_lifetime = u32::from_be_bytes(option[4..8].try_into().unwrap());
+    let preferred_lifetime = u32::from_be_bytes(option[8..12].try_into().unwrap());
+    let prefix: Ipv6Octets = option[16..32].try_into().unwrap();
+    if valid_lifetime == 0
+        || preferred_lifetime > valid_lifetime
+        || is_link_local(&prefix)
+        || is_multicast(&prefix)
+    {
+        return None;
+    }
+    Some(Prefix {
+        prefix,
+        valid_lifetime,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec::Vec;
+
+    const MAC: [u8; 6] = [0x34, 0x85, 0x18, 0x01, 0x02, 0x03];
+    const ROUTER: Ipv6Octets = [0xfe, 0x80, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1];
+    const ALL_NODES: Ipv6Octets = [0xff, 2, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1];
+    const PREFIX: Ipv6Octets = [0x20, 0x01, 0x0d, 0xb8, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+
+    fn prefix_option(len: u8, flags: u8, valid: u32, preferred: u32) -> [u8; 32] {
+        let mut option = [0; 32];
+        option[..4].copy_from_slice(&[OPTION_PREFIX_INFORMATION, 4, len, flags]);
+        option[4..8].copy_from_slice(&valid.to_be_bytes());
+        option[8..12].copy_from_slice(&preferred.to_be_bytes());
+        option[16..].copy_from_slice(&PREFIX);
+        option
+    }
+
+    fn advertisement(options: &[&[u8]]) -> Vec<u8> {
+        let mut icmp = alloc::vec![0; 16];
+        icmp[0] = TYPE_ROUTER_ADVERTISEMENT;
+        icmp[4] = 64;
+        icmp[6..8].copy_from_slice(&1800u16.to_be_bytes());
+        for option in options {
+            icmp.extend_from_slice(option);
+        }
+        let checksum = checksum(&ROUTER, &ALL_NODES, &icmp);
+        icmp[2..4].copy_from_slice(&checksum.to_be_bytes());
+
+        let mut packet = alloc::vec![0; IPV6_HEADER_LEN];
+        packet.extend_from_slice(&icmp);
+        write_ipv6_header(&mut packet, &ROUTER, &ALL_NODES);
+        packet
+    }
+
+    #[test]
+    fn addresses_use_the_modified_eui64() {
+        assert_eq!(
+            link_local_address(MAC),
+            [0xfe, 0x80, 0, 0, 0, 0, 0, 0, 0x36, 0x85, 0x18, 0xff, 0xfe, 0x01, 0x02, 0x03]
+        );
+        assert_eq!(address(&PREFIX, MAC)[..8], PREFIX[..8]);
+        assert_eq!(address(&PREFIX, MAC)[8..], interface_id(MAC));
+    }
+
+    #[test]
+    fn solicitation_is_checksummed() {
+        let packet = router_solicitation(MAC);
+        assert_eq!(packet[0] >> 4, 6);
+        assert_eq!(packet[7], HOP_LIMIT);
+        assert_eq!(packet[24..40], ALL_ROUTERS);
+        let icmp = &packet[IPV6_HEADER_LEN..];
+        assert_eq!(icmp[0], TYPE_ROUTER_SOLICITATION);
+        assert_eq!(icmp[10..16], MAC);
+        assert_eq!(checksum(&link_local_address(MAC), &ALL_ROUTERS, icmp), 0);
+    }
+
+    #[test]
+    fn advertisement_with_prefix() {
+        let packet = advertisement(&[
+            // Source link layer address of the router
+            &[1, 1, 0, 1, 2, 3, 4, 5],
+            &prefix_option(64, 0xc0, 86400, 14400),
+        ]);
+        let advertisement = parse_router_advertisement(&packet).unwrap();
+        assert_eq!(advertisement.router, ROUTER);
+        assert_eq!(advertisement.router_lifetime, 1800);
+        assert_eq!(
+            advertisement.prefix,
+            Some(Prefix {
+                prefix: PREFIX,
+                valid_lifetime: 86400
+            })
+        );
+    }
+
+    #[test]
+    fn unusable_prefixes_are_skipped() {
+        for option in [
+            prefix_option(48, 0xc0, 86400, 14400),
+            // Only on-link, not autonomous
+            prefix_option(64, 0x80, 86400, 14400),
+            prefix_option(64, 0xc0, 0, 0),
+            prefix_option(64, 0xc0, 100, 200),
+        ] {
+            let packet = advertisement(&[&option]);
+            assert_eq!(parse_router_advertisement(&packet).unwrap().prefix, None);
+        }
+        let usable = prefix_option(64, 0x40, INFINITE_LIFETIME, INFINITE_LIFETIME);
+        let packet = advertisement(&[&prefix_option(48, 0xc0, 1, 1), &usable]);
+        assert!(parse_router_advertisement(&packet)
+            .unwrap()
+            .prefix
+            .is_some());
+    }
+
+    #[test]
+    fn invalid_advertisements_are_rejected() {
+        let packet = advertisement(&[&prefix_option(64, 0xc0, 86400, 14400)]);
+        let mut corrupt = packet.clone();
+        corrupt[IPV6_HEADER_LEN + 40] ^= 1;
+        assert_eq!(parse_router_advertisement(&corrupt), None);
+
+        let mut forwarded = packet.clone();
+        forwarded[7] = 254;
+        assert_eq!(parse_router_advertisement(&forwarded), None);
+
+        // An option with length zero would never end
+        let packet = advertisement(&[&[3, 0, 0, 0, 0, 0, 0, 0]]);
+        assert_eq!(parse_router_advertisement(&packet), None);
+
+        let solicitation = router_solicitation(MAC);
+        assert_eq!(parse_router_advertisement(&solicitation), None);
+        assert_eq!(parse_router_advertisement(&packet[..30]), None);
+    }
+}
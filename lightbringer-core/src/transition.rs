@@ -0,0 +1,114 @@
+use crate::light_state::LightState;
+use core::cell::Cell;
+use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_time::{Duration, Instant};
+
+/// Transition used when the writer of a light state did not ask for one
+pub const DEFAULT_TRANSITION_MS: u32 = 150;
+
+/// Duration of the transition towards the next light state, set by whoever writes that state
+pub struct NextTransition(Mutex<NoopRawMutex, Cell<Option<u32>>>);
+
+impl NextTransition {
+    pub const fn new() -> Self {
+        Self(Mutex::new(Cell::new(None)))
+    }
+
+    /// Should be called right before updating the light state, `0` switches immediately
+    pub fn set(&self, millis: u32) {
+        self.0.lock(|next| next.set(Some(millis)));
+    }
+
+    pub fn take(&self) -> Duration {
+        let millis = self
+            .0
+            .lock(|next| next.take())
+            .unwrap_or(DEFAULT_TRANSITION_MS);
+        Duration::from_millis(millis as u64)
+    }
+}
+
+impl Default for NextTransition {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Linear interpolation between two light states
+pub struct Fade {
+    from: LightState,
+    to: LightState,
+    start: Instant,
+    duration: Duration,
+}
+
+impl Fade {
+    pub fn new(from: LightState, to: LightState, start: Instant, duration: Duration) -> Self {
+        Self {
+            from,
+            to,
+            start,
+            duration,
+        }
+    }
+
+    pub fn target(&self) -> LightState {
+        self.to
+    }
+
+    pub fn end(&self) -> Instant {
+        self.start + self.duration
+    }
+
+    pub fn is_done(&self, now: Instant) -> bool {
+        now >= self.end()
+    }
+
+    /// The light state at `now`, fades are retargeted by starting a new one from this state
+    pub fn at(&self, now: Instant) -> LightState {
+        if self.is_done(now) {
+            return self.to;
+        }
+        let elapsed = now.saturating_duration_since(self.start).as_ticks() as i64;
+        let total = self.duration.as_ticks() as i64;
+        let lerp =
+            |from: u16, to: u16| (from as i64 + (to as i64 - from as i64) * elapsed / total) as u16;
+        LightState::new(
+            lerp(self.from.brightness, self.to.brightness),
+            lerp(self.from.temperature, self.to.temperature),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fade_interpolates_until_its_end() {
+        let fade = Fade::new(
+            LightState::new(0, 3000),
+            LightState::new(1000, 5000),
+            Instant::from_millis(100),
+            Duration::from_millis(200),
+        );
+        assert_eq!(fade.at(Instant::from_millis(0)).brightness, 0);
+        let half = fade.at(Instant::from_millis(200));
+        assert_eq!((half.brightness, half.temperature), (500, 4000));
+        assert!(!fade.is_done(Instant::from_millis(299)));
+        assert!(fade.is_done(Instant::from_millis(300)));
+        assert_eq!(fade.at(Instant::from_millis(400)).brightness, 1000);
+    }
+
+    #[test]
+    fn next_transition_is_taken_once() {
+        let next = NextTransition::new();
+        next.set(0);
+        assert_eq!(next.take(), Duration::from_millis(0));
+        assert_eq!(
+            next.take(),
+            Duration::from_millis(DEFAULT_TRANSITION_MS as u64)
+        );
+    }
+}
@@ -0,0 +1,64 @@
+//! Parsing of the `http://` URLs firmware is downloaded from
+
+/// The parts of a `http://host[:port][/path]` URL, HTTPS is not supported
+#[derive(Debug, Eq, PartialEq)]
+pub struct ParsedUrl<'a> {
+    pub host: &'a str,
+    pub port: u16,
+    pub path: &'a str,
+}
+
+pub fn parse_url(url: &str) -> Option<ParsedUrl<'_>> {
+    let rest = url.strip_prefix("http://")?;
+    let (authority, path) = match rest.find('/') {
+        Some(index) => rest.split_at(index),
+        None => (rest, "/"),
+    };
+    let (host, port) = match authority.split_once(':') {
+        Some((host, port)) => (host, port.parse().ok()?),
+        None => (authority, 80),
+    };
+    let valid = !host.is_empty()
+        && path.bytes().all(|b| b.is_ascii_graphic())
+        && host
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'.');
+    valid.then_some(ParsedUrl { host, port, path })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parts(url: &str) -> Option<(&str, u16, &str)> {
+        parse_url(url).map(|url| (url.host, url.port, url.path))
+    }
+
+    #[test]
+    fn parse_url_defaults() {
+        assert_eq!(parts("http://example.com"), Some(("example.com", 80, "/")));
+        assert_eq!(
+            parts("http://example.com/manifest.json"),
+            Some(("example.com", 80, "/manifest.json"))
+        );
+    }
+
+    #[test]
+    fn parse_url_with_port_and_path() {
+        assert_eq!(
+            parts("http://192.168.1.2:8080/fw/lightbringer.bin?v=1"),
+            Some(("192.168.1.2", 8080, "/fw/lightbringer.bin?v=1"))
+        );
+    }
+
+    #[test]
+    fn parse_url_rejects_invalid_urls() {
+        assert_eq!(parts("https://example.com/"), None);
+        assert_eq!(parts("example.com/"), None);
+        assert_eq!(parts("http:///path"), None);
+        assert_eq!(parts("http://example.com:http/"), None);
+        assert_eq!(parts("http://example.com:65536/"), None);
+        assert_eq!(parts("http://user@example.com/"), None);
+        assert_eq!(parts("http://example.com/a b"), None);
+    }
+}
@@ -0,0 +1,31 @@
+//! Comparison of the firmware versions in update manifests
+
+/// Whether `candidate` is a higher `major.minor.patch` version than `current`
+pub fn is_newer(candidate: &str, current: &str) -> bool {
+    let parse = |version: &str| {
+        let mut parts = version.split('.').map(|part| part.parse::<u32>().ok());
+        [(); 3].map(|_| parts.next().flatten().unwrap_or(0))
+    };
+    parse(candidate) > parse(current)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn is_newer_compares_numerically() {
+        assert!(is_newer("0.10.0", "0.9.0"));
+        assert!(is_newer("1.0.0", "0.99.99"));
+        assert!(is_newer("0.2.1", "0.2.0"));
+        assert!(!is_newer("0.9.0", "0.10.0"));
+        assert!(!is_newer("0.2.0", "0.2.0"));
+    }
+
+    #[test]
+    fn is_newer_fills_missing_parts_with_zero() {
+        assert!(!is_newer("1.0", "1.0.0"));
+        assert!(is_newer("1.1", "1.0.5"));
+        assert!(!is_newer("garbage", "0.0.0"));
+    }
+}
@@ -0,0 +1,840 @@
+use crate::auth::{store_auth_config, AccessLevel, SharedAuthConfig};
+use crate::color_storage::SaveSignal;
+use crate::device_name::{is_valid_device_name, store_device_name, DeviceName, SharedDeviceName};
+use crate::dimming_curve::{store_dimming_curve, DimmingCurve, SharedDimmingCurve, CURVE_POINTS};
+use crate::firmware_signature::SIGNATURE_LEN;
+use crate::health_check::{
+    read_boot_history, store_health_check_config, BootHistory, HealthCheckConfig,
+    SharedHealthCheckConfig,
+};
+use crate::http::MAX_LISTENERS;
+use crate::http_client::Url;
+use crate::journal::{SharedJournal, StorageStatus};
+use crate::light_state::LightState;
+use crate::mqtt_config::{
+    store_mqtt_config, MqttConfig, SharedMqttConfig, DEFAULT_PORT, MAX_HOST_LEN, MAX_PASSWORD_LEN,
+    MAX_USERNAME_LEN,
+};
+use crate::network_config::{
+    store_ipv4_settings, Ipv4Settings, SharedIpv4Settings, MAX_DNS_SERVERS,
+};
+use crate::ota::{target_partition_size, OtaState, SharedOtaProgress};
+use crate::ota_data::{OtaData, SlotState};
+use crate::ota_pull::{store_manifest_url, PullRequest, SharedManifestUrl};
+use crate::presets::{store_presets, Preset, PresetName, SharedPresets, MAX_PRESETS};
+use crate::transition::NextTransition;
+use crate::value_synchronizer::ValueSynchronizer;
+use crate::wifi::{ScanResult, WifiProvisioning, MAX_SCAN_RESULTS};
+use crate::wifi_credentials::{
+    store_wifi_networks, WifiCredentials, WifiNetworks, MAX_NETWORKS, MAX_SSID_LEN,
+};
+use crate::ESP_APP_DESC;
+use build_time::build_time_local;
+use core::fmt::Write;
+use embassy_net::{Ipv4Address, Ipv4Cidr, Stack, StaticConfigV4};
+use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+use embassy_time::Instant;
+use esp_hal::rng::Rng;
+use esp_hal::rtc_cntl::reset_reason;
+use esp_hal::system::Cpu;
+use esp_ota_nostd::get_booted_partition;
+use esp_storage::FlashStorage;
+use lightbringer_core::url::parse_url;
+use picoserve::response::StatusCode;
+use serde::{Deserialize, Serialize};
+
+/// Changes are applied even when storing them fails, but the client learns that they are lost
+/// on restart
+fn persisted(stored: bool) -> Result<(), (StatusCode, &'static str)> {
+    match stored {
+        true => Ok(()),
+        false => Err((
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "The change was applied, but storing it failed so it is lost on restart\n",
+        )),
+    }
+}
+
+#[derive(Serialize)]
+pub struct StateResponse {
+    brightness: f32,
+    temperature: u16,
+    cold: u16,
+    warm: u16,
+}
+
+impl StateResponse {
+    fn new(state: LightState, curve: &DimmingCurve) -> Self {
+        let (cold, warm) = state.channels(curve);
+        Self {
+            brightness: state.brightness_fraction(),
+            temperature: state.temperature,
+            cold,
+            warm,
+        }
+    }
+}
+
+/// Body of `PUT`/`PATCH /api/state`, the light can be set either by brightness (`0.0..=1.0`)
+/// and temperature (Kelvin) or by the PWM values of its channels
+#[derive(Deserialize)]
+pub struct StateUpdate {
+    brightness: Option<f32>,
+    temperature: Option<u16>,
+    cold: Option<u16>,
+    warm: Option<u16>,
+    /// Duration of the transition in milliseconds
+    transition: Option<u32>,
+    /// Whether the new state is written to flash, defaults to `true`
+    save: Option<bool>,
+}
+
+impl StateUpdate {
+    fn is_complete(&self) -> bool {
+        (self.brightness.is_some() && self.temperature.is_some())
+            || (self.cold.is_some() && self.warm.is_some())
+    }
+
+    fn apply(&self, state: &mut LightState, curve: &DimmingCurve) {
+        if self.cold.is_some() || self.warm.is_some() {
+            let (cold, warm) = state.channels(curve);
+            *state = LightState::from_channels(
+                self.cold.unwrap_or(cold),
+                self.warm.unwrap_or(warm),
+                curve,
+            );
+        }
+        if let Some(brightness) = self.brightness {
+            let brightness = brightness.clamp(0.0, 1.0) * u16::MAX as f32;
+            state.brightness = libm::roundf(brightness) as u16;
+        }
+        if let Some(temperature) = self.temperature {
+            *state = LightState::new(state.brightness, temperature);
+        }
+    }
+}
+
+pub fn get_state(
+    data: &ValueSynchronizer<MAX_LISTENERS, NoopRawMutex, LightState>,
+    curve: &SharedDimmingCurve,
+) -> picoserve::response::Json<StateResponse> {
+    picoserve::response::Json(StateResponse::new(data.read_clone(), &curve.read_clone()))
+}
+
+/// Applies an update, a `PUT` (not `partial`) must describe the full state
+pub fn update_state(
+    data: &ValueSynchronizer<MAX_LISTENERS, NoopRawMutex, LightState>,
+    curve: &SharedDimmingCurve,
+    transition: &NextTransition,
+    save: &SaveSignal,
+    update: StateUpdate,
+    partial: bool,
+) -> Result<picoserve::response::Json<StateResponse>, (StatusCode, &'static str)> {
+    if !partial && !update.is_complete() {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "Expected both brightness and temperature or both cold and warm\n",
+        ));
+    }
+
+    let current_curve = curve.read_clone();
+    if let Some(millis) = update.transition {
+        transition.set(millis);
+    }
+    data.update(|state| update.apply(state, &current_curve));
+    if update.save.unwrap_or(true) {
+        save.signal(());
+    }
+    Ok(get_state(data, curve))
+}
+
+#[derive(Serialize)]
+pub struct PresetResponse {
+    name: PresetName,
+    brightness: f32,
+    temperature: u16,
+}
+
+pub type PresetsResponse = heapless::Vec<PresetResponse, MAX_PRESETS>;
+
+/// Body of `POST /api/presets`, missing values are taken from the current state
+#[derive(Deserialize)]
+pub struct PresetUpdate {
+    name: PresetName,
+    brightness: Option<f32>,
+    temperature: Option<u16>,
+}
+
+/// Body of `DELETE /api/presets`
+#[derive(Deserialize)]
+pub struct PresetRemoval {
+    name: PresetName,
+}
+
+/// Body of `POST /api/presets/recall`
+#[derive(Deserialize)]
+pub struct PresetRecall {
+    name: PresetName,
+    /// Duration of the transition in milliseconds
+    transition: Option<u32>,
+}
+
+pub fn get_presets(presets: &SharedPresets) -> picoserve::response::Json<PresetsResponse> {
+    let response = presets.lock(|presets| {
+        presets
+            .borrow()
+            .iter()
+            .map(|preset| PresetResponse {
+                name: preset.name.clone(),
+                brightness: preset.state.brightness_fraction(),
+                temperature: preset.state.temperature,
+            })
+            .collect()
+    });
+    picoserve::response::Json(response)
+}
+
+/// Adds a preset or replaces the one with the same name
+pub fn save_preset(
+    presets: &SharedPresets,
+    data: &ValueSynchronizer<MAX_LISTENERS, NoopRawMutex, LightState>,
+    journal: &SharedJournal,
+    update: PresetUpdate,
+) -> Result<picoserve::response::Json<PresetsResponse>, (StatusCode, &'static str)> {
+    if update.name.trim().is_empty() {
+        return Err((StatusCode::BAD_REQUEST, "The name may not be empty\n"));
+    }
+
+    let mut state = data.read_clone();
+    if let Some(brightness) = update.brightness {
+        let brightness = brightness.clamp(0.0, 1.0) * u16::MAX as f32;
+        state.brightness = libm::roundf(brightness) as u16;
+    }
+    if let Some(temperature) = update.temperature {
+        state = LightState::new(state.brightness, temperature);
+    }
+    let preset = Preset {
+        name: update.name,
+        state,
+    };
+    let updated = presets
+        .lock(|presets| {
+            let mut presets = presets.borrow_mut();
+            presets.add(preset)?;
+            Ok(presets.clone())
+        })
+        .map_err(|_: Preset| (StatusCode::BAD_REQUEST, "Too many presets\n"))?;
+    persisted(store_presets(journal, updated))?;
+    Ok(get_presets(presets))
+}
+
+pub fn remove_preset(
+    presets: &SharedPresets,
+    journal: &SharedJournal,
+    removal: PresetRemoval,
+) -> Result<picoserve::response::Json<PresetsResponse>, (StatusCode, &'static str)> {
+    let updated = presets
+        .lock(|presets| {
+            let mut presets = presets.borrow_mut();
+            presets.remove(&removal.name).then(|| presets.clone())
+        })
+        .ok_or((StatusCode::NOT_FOUND, "Unknown preset\n"))?;
+    persisted(store_presets(journal, updated))?;
+    Ok(get_presets(presets))
+}
+
+/// Applies a preset like a state update, so every client sees the change
+pub fn recall_preset(
+    presets: &SharedPresets,
+    data: &ValueSynchronizer<MAX_LISTENERS, NoopRawMutex, LightState>,
+    curve: &SharedDimmingCurve,
+    transition: &NextTransition,
+    save: &SaveSignal,
+    recall: PresetRecall,
+) -> Result<picoserve::response::Json<StateResponse>, (StatusCode, &'static str)> {
+    let preset = presets
+        .lock(|presets| presets.borrow().get(&recall.name))
+        .ok_or((StatusCode::NOT_FOUND, "Unknown preset\n"))?;
+    if let Some(millis) = recall.transition {
+        transition.set(millis);
+    }
+    data.update(|state| *state = preset);
+    save.signal(());
+    Ok(get_state(data, curve))
+}
+
+#[derive(Serialize)]
+pub struct CurveResponse {
+    table: [u16; CURVE_POINTS],
+}
+
+/// Body of `PUT /api/curve`, exactly one of the fields should be set
+#[derive(Deserialize)]
+pub struct CurveUpdate {
+    /// One of `"square"` (the default), `"cie1931"` or `"linear"`
+    preset: Option<CurvePreset>,
+    gamma: Option<f32>,
+    /// Output levels for evenly spaced brightness values, may not decrease
+    table: Option<[u16; CURVE_POINTS]>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CurvePreset {
+    Square,
+    Cie1931,
+    Linear,
+}
+
+pub fn get_curve(curve: &SharedDimmingCurve) -> picoserve::response::Json<CurveResponse> {
+    picoserve::response::Json(CurveResponse {
+        table: curve.read_clone().table(),
+    })
+}
+
+pub fn update_curve(
+    curve: &SharedDimmingCurve,
+    journal: &SharedJournal,
+    update: CurveUpdate,
+) -> Result<picoserve::response::Json<CurveResponse>, (StatusCode, &'static str)> {
+    let new_curve = match update {
+        CurveUpdate {
+            preset: Some(CurvePreset::Square),
+            gamma: None,
+            table: None,
+        } => DimmingCurve::square(),
+        CurveUpdate {
+            preset: Some(CurvePreset::Cie1931),
+            gamma: None,
+            table: None,
+        } => DimmingCurve::cie1931(),
+        CurveUpdate {
+            preset: Some(CurvePreset::Linear),
+            gamma: None,
+            table: None,
+        } => DimmingCurve::linear(),
+        CurveUpdate {
+            preset: None,
+            gamma: Some(gamma),
+            table: None,
+        } if gamma > 0.0 => DimmingCurve::gamma(gamma),
+        CurveUpdate {
+            preset: None,
+            gamma: None,
+            table: Some(table),
+        } => DimmingCurve::from_table(table)
+            .ok_or((StatusCode::BAD_REQUEST, "The table may not decrease\n"))?,
+        _ => {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                "Expected one of preset, a positive gamma or table\n",
+            ))
+        }
+    };
+
+    curve.update(|c| *c = new_curve);
+    persisted(store_dimming_curve(journal, new_curve))?;
+    Ok(get_curve(curve))
+}
+
+#[derive(Serialize)]
+pub struct ScanResponse {
+    networks: heapless::Vec<ScanResult, MAX_SCAN_RESULTS>,
+}
+
+/// Returns the results of the previous scan and starts a new one
+pub fn get_scan(provisioning: &WifiProvisioning) -> picoserve::response::Json<ScanResponse> {
+    let networks = provisioning.scan_results();
+    provisioning.request_scan();
+    picoserve::response::Json(ScanResponse { networks })
+}
+
+#[derive(Serialize)]
+pub struct NetworkResponse {
+    ssid: heapless::String<MAX_SSID_LEN>,
+    priority: u8,
+}
+
+/// The known networks, without their passwords
+#[derive(Serialize)]
+pub struct NetworksResponse {
+    networks: heapless::Vec<NetworkResponse, MAX_NETWORKS>,
+}
+
+impl NetworksResponse {
+    fn new(networks: &WifiNetworks) -> Self {
+        Self {
+            networks: networks
+                .iter()
+                .map(|n| NetworkResponse {
+                    ssid: n.ssid.clone(),
+                    priority: n.priority,
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Body of `DELETE /api/wifi/networks`
+#[derive(Deserialize)]
+pub struct NetworkRemoval {
+    ssid: heapless::String<MAX_SSID_LEN>,
+}
+
+pub fn get_wifi_networks(
+    provisioning: &WifiProvisioning,
+) -> picoserve::response::Json<NetworksResponse> {
+    picoserve::response::Json(NetworksResponse::new(&provisioning.networks()))
+}
+
+/// Adds a network or replaces the one with the same SSID
+pub fn add_wifi_network(
+    journal: &SharedJournal,
+    provisioning: &WifiProvisioning,
+    credentials: WifiCredentials,
+) -> Result<picoserve::response::Json<NetworksResponse>, (StatusCode, &'static str)> {
+    if credentials.ssid.is_empty() {
+        return Err((StatusCode::BAD_REQUEST, "The SSID may not be empty\n"));
+    }
+    if !credentials.password.is_empty() && credentials.password.len() < 8 {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "The password must be empty or at least 8 characters\n",
+        ));
+    }
+
+    let networks = provisioning
+        .update_networks(|networks| {
+            networks.add(credentials)?;
+            Ok(networks.clone())
+        })
+        .map_err(|_| (StatusCode::BAD_REQUEST, "Too many networks\n"))?;
+    let response = NetworksResponse::new(&networks);
+    persisted(store_wifi_networks(journal, networks))?;
+    Ok(picoserve::response::Json(response))
+}
+
+pub fn remove_wifi_network(
+    journal: &SharedJournal,
+    provisioning: &WifiProvisioning,
+    removal: NetworkRemoval,
+) -> Result<picoserve::response::Json<NetworksResponse>, (StatusCode, &'static str)> {
+    let networks = provisioning
+        .update_networks(|networks| networks.remove(&removal.ssid).then(|| networks.clone()))
+        .ok_or((StatusCode::NOT_FOUND, "Unknown network\n"))?;
+    let response = NetworksResponse::new(&networks);
+    persisted(store_wifi_networks(journal, networks))?;
+    Ok(picoserve::response::Json(response))
+}
+
+#[derive(Serialize)]
+pub struct DeviceResponse {
+    name: DeviceName,
+    version: &'static str,
+}
+
+/// Body of `PUT /api/device`
+#[derive(Deserialize)]
+pub struct DeviceUpdate {
+    name: DeviceName,
+}
+
+pub fn get_device(name: &SharedDeviceName) -> picoserve::response::Json<DeviceResponse> {
+    picoserve::response::Json(DeviceResponse {
+        name: name.read_clone(),
+        version: ESP_APP_DESC.version(),
+    })
+}
+
+pub fn update_device(
+    name: &SharedDeviceName,
+    journal: &SharedJournal,
+    update: DeviceUpdate,
+) -> Result<picoserve::response::Json<DeviceResponse>, (StatusCode, &'static str)> {
+    if !is_valid_device_name(&update.name) {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "The name may only contain letters, digits and dashes, and may not start or end with a dash\n",
+        ));
+    }
+
+    let stored = store_device_name(journal, &update.name);
+    name.update(|n| *n = update.name);
+    persisted(stored)?;
+    Ok(get_device(name))
+}
+
+fn format<const N: usize>(value: impl core::fmt::Display) -> heapless::String<N> {
+    let mut s = heapless::String::new();
+    write!(s, "{value}").unwrap();
+    s
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Ipv4Mode {
+    Dhcp,
+    Static,
+}
+
+/// Body and response of `/api/network/config`, addresses are written like `192.168.1.20/24`
+#[derive(Serialize, Deserialize)]
+pub struct NetworkConfigBody {
+    mode: Ipv4Mode,
+    address: Option<heapless::String<18>>,
+    gateway: Option<heapless::String<15>>,
+    #[serde(default)]
+    dns: heapless::Vec<heapless::String<15>, MAX_DNS_SERVERS>,
+}
+
+impl NetworkConfigBody {
+    fn new(settings: Ipv4Settings) -> Self {
+        match settings {
+            Ipv4Settings::Dhcp => Self {
+                mode: Ipv4Mode::Dhcp,
+                address: None,
+                gateway: None,
+                dns: heapless::Vec::new(),
+            },
+            Ipv4Settings::Static(config) => Self {
+                mode: Ipv4Mode::Static,
+                address: Some(format(config.address)),
+                gateway: config.gateway.map(format),
+                dns: config.dns_servers.iter().map(format).collect(),
+            },
+        }
+    }
+
+    fn settings(&self) -> Option<Ipv4Settings> {
+        if let Ipv4Mode::Dhcp = self.mode {
+            return Some(Ipv4Settings::Dhcp);
+        }
+        let (address, prefix_len) = self.address.as_ref()?.split_once('/')?;
+        let address: Ipv4Address = address.parse().ok()?;
+        let prefix_len: u8 = prefix_len.parse().ok()?;
+        if address.is_unspecified() || !(1..=32).contains(&prefix_len) {
+            return None;
+        }
+        let gateway = match &self.gateway {
+            Some(gateway) => Some(gateway.parse().ok()?),
+            None => None,
+        };
+        let dns_servers = self
+            .dns
+            .iter()
+            .map(|server| server.parse().ok())
+            .collect::<Option<_>>()?;
+        Some(Ipv4Settings::Static(StaticConfigV4 {
+            address: Ipv4Cidr::new(address, prefix_len),
+            gateway,
+            dns_servers,
+        }))
+    }
+}
+
+pub fn get_network_config(
+    ipv4: &SharedIpv4Settings,
+) -> picoserve::response::Json<NetworkConfigBody> {
+    picoserve::response::Json(NetworkConfigBody::new(ipv4.read_clone()))
+}
+
+/// Changes how the lamp gets its address, which is applied immediately
+pub fn update_network_config(
+    ipv4: &SharedIpv4Settings,
+    journal: &SharedJournal,
+    update: NetworkConfigBody,
+) -> Result<picoserve::response::Json<NetworkConfigBody>, (StatusCode, &'static str)> {
+    let settings = update.settings().ok_or((
+        StatusCode::BAD_REQUEST,
+        "Expected an address with prefix length and valid gateway and dns addresses\n",
+    ))?;
+
+    let stored = store_ipv4_settings(journal, settings.clone());
+    ipv4.update(|s| *s = settings);
+    persisted(stored)?;
+    Ok(get_network_config(ipv4))
+}
+
+#[derive(Serialize)]
+pub struct Ipv4Status {
+    address: heapless::String<18>,
+    gateway: Option<heapless::String<15>>,
+    dns: heapless::Vec<heapless::String<15>, MAX_DNS_SERVERS>,
+}
+
+#[derive(Serialize)]
+pub struct Ipv6Status {
+    address: heapless::String<43>,
+    gateway: Option<heapless::String<39>>,
+}
+
+/// The addresses the lamp currently has on the network it's connected to
+#[derive(Serialize)]
+pub struct NetworkStatus {
+    link_up: bool,
+    ipv4: Option<Ipv4Status>,
+    ipv6: Option<Ipv6Status>,
+}
+
+pub fn get_network_status(stack: Stack<'_>) -> picoserve::response::Json<NetworkStatus> {
+    let ipv4 = stack.config_v4().map(|config| Ipv4Status {
+        address: format(config.address),
+        gateway: config.gateway.map(format),
+        dns: config.dns_servers.iter().map(format).collect(),
+    });
+    let ipv6 = stack.config_v6().map(|config| Ipv6Status {
+        address: format(config.address),
+        gateway: config.gateway.map(format),
+    });
+    picoserve::response::Json(NetworkStatus {
+        link_up: stack.is_link_up(),
+        ipv4,
+        ipv6,
+    })
+}
+
+/// The MQTT config without the password
+#[derive(Serialize)]
+pub struct MqttResponse {
+    host: heapless::String<MAX_HOST_LEN>,
+    port: u16,
+    username: heapless::String<MAX_USERNAME_LEN>,
+    has_password: bool,
+}
+
+/// Body of `PUT /api/mqtt`, an empty host disables the client and a missing password is kept
+#[derive(Deserialize)]
+pub struct MqttUpdate {
+    host: heapless::String<MAX_HOST_LEN>,
+    port: Option<u16>,
+    #[serde(default)]
+    username: heapless::String<MAX_USERNAME_LEN>,
+    password: Option<heapless::String<MAX_PASSWORD_LEN>>,
+}
+
+pub fn get_mqtt(mqtt: &SharedMqttConfig) -> picoserve::response::Json<MqttResponse> {
+    let config = mqtt.read_clone();
+    picoserve::response::Json(MqttResponse {
+        host: config.host,
+        port: config.port,
+        username: config.username,
+        has_password: !config.password.is_empty(),
+    })
+}
+
+pub fn update_mqtt(
+    mqtt: &SharedMqttConfig,
+    journal: &SharedJournal,
+    update: MqttUpdate,
+) -> Result<picoserve::response::Json<MqttResponse>, (StatusCode, &'static str)> {
+    if update.port == Some(0) {
+        return Err((StatusCode::BAD_REQUEST, "The port may not be 0\n"));
+    }
+
+    let new_config = MqttConfig {
+        host: update.host,
+        port: update.port.unwrap_or(DEFAULT_PORT),
+        username: update.username,
+        password: update
+            .password
+            .unwrap_or_else(|| mqtt.read(|c| c.password.clone())),
+    };
+    let stored = store_mqtt_config(journal, new_config.clone());
+    mqtt.update(|c| *c = new_config);
+    persisted(stored)?;
+    Ok(get_mqtt(mqtt))
+}
+
+#[derive(Serialize)]
+pub struct AuthResponse {
+    user_password_set: bool,
+    admin_password_set: bool,
+}
+
+/// Body of `PUT /api/auth`, a missing password is kept and an empty one removed
+#[derive(Deserialize)]
+pub struct AuthUpdate {
+    user_password: Option<heapless::String<64>>,
+    admin_password: Option<heapless::String<64>>,
+}
+
+pub fn get_auth(auth: &SharedAuthConfig) -> picoserve::response::Json<AuthResponse> {
+    auth.lock(|auth| {
+        let auth = auth.borrow();
+        picoserve::response::Json(AuthResponse {
+            user_password_set: auth.has_password(AccessLevel::User),
+            admin_password_set: auth.has_password(AccessLevel::Admin),
+        })
+    })
+}
+
+pub fn update_auth(
+    auth: &SharedAuthConfig,
+    journal: &SharedJournal,
+    rng: Rng,
+    update: AuthUpdate,
+) -> Result<picoserve::response::Json<AuthResponse>, (StatusCode, &'static str)> {
+    let new_auth = auth.lock(|auth| {
+        let mut auth = auth.borrow_mut();
+        if let Some(password) = &update.user_password {
+            auth.set_password(AccessLevel::User, password, rng);
+        }
+        if let Some(password) = &update.admin_password {
+            auth.set_password(AccessLevel::Admin, password, rng);
+        }
+        auth.clone()
+    });
+    persisted(store_auth_config(journal, new_auth))?;
+    Ok(get_auth(auth))
+}
+
+#[derive(Serialize)]
+pub struct OtaStatusResponse {
+    state: OtaState,
+    written: usize,
+    total: usize,
+    /// Largest upload that fits in the OTA partition, including the signature
+    max_size: Option<usize>,
+}
+
+pub fn get_ota_status(
+    progress: &SharedOtaProgress,
+) -> picoserve::response::Json<OtaStatusResponse> {
+    let progress = progress.lock(|progress| progress.get());
+    let max_size = target_partition_size().map(|size| size + SIGNATURE_LEN);
+    picoserve::response::Json(OtaStatusResponse {
+        state: progress.state,
+        written: progress.written,
+        total: progress.total,
+        max_size,
+    })
+}
+
+/// Body of `POST /api/ota/pull` and `PUT /api/ota/manifest`
+#[derive(Deserialize, Serialize)]
+pub struct UrlBody {
+    url: Url,
+}
+
+pub fn pull_firmware(
+    progress: &SharedOtaProgress,
+    request: &PullRequest,
+    body: UrlBody,
+) -> Result<(StatusCode, &'static str), (StatusCode, &'static str)> {
+    if parse_url(&body.url).is_none() {
+        return Err((StatusCode::BAD_REQUEST, "Expected a http:// URL\n"));
+    }
+    if progress.lock(|progress| progress.get().state == OtaState::Writing) {
+        return Err((StatusCode::CONFLICT, "Another update is in progress\n"));
+    }
+    request.signal(body.url);
+    Ok((StatusCode::ACCEPTED, "Downloading firmware, see /api/ota\n"))
+}
+
+#[derive(Serialize)]
+pub struct ManifestResponse {
+    url: Url,
+    /// Version of the running firmware
+    version: &'static str,
+}
+
+pub fn get_manifest(manifest: &SharedManifestUrl) -> picoserve::response::Json<ManifestResponse> {
+    picoserve::response::Json(ManifestResponse {
+        url: manifest.read_clone(),
+        version: ESP_APP_DESC.version(),
+    })
+}
+
+/// An empty URL disables automatic updates
+pub fn update_manifest(
+    manifest: &SharedManifestUrl,
+    journal: &SharedJournal,
+    body: UrlBody,
+) -> Result<picoserve::response::Json<ManifestResponse>, (StatusCode, &'static str)> {
+    if !body.url.is_empty() && parse_url(&body.url).is_none() {
+        return Err((StatusCode::BAD_REQUEST, "Expected a http:// URL\n"));
+    }
+    let stored = store_manifest_url(journal, &body.url);
+    manifest.update(|url| *url = body.url);
+    persisted(stored)?;
+    Ok(get_manifest(manifest))
+}
+
+pub fn get_health_check(
+    health: &SharedHealthCheckConfig,
+) -> picoserve::response::Json<HealthCheckConfig> {
+    picoserve::response::Json(health.lock(|health| health.get()))
+}
+
+/// Applies to the next firmware update
+pub fn update_health_check(
+    health: &SharedHealthCheckConfig,
+    journal: &SharedJournal,
+    update: HealthCheckConfig,
+) -> Result<picoserve::response::Json<HealthCheckConfig>, (StatusCode, &'static str)> {
+    if update.timeout_secs <= update.network_secs {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "The timeout must be longer than the network time\n",
+        ));
+    }
+    let stored = store_health_check_config(journal, update);
+    health.lock(|health| health.set(update));
+    persisted(stored)?;
+    Ok(get_health_check(health))
+}
+
+pub fn get_boot_history(journal: &SharedJournal) -> picoserve::response::Json<BootHistory> {
+    picoserve::response::Json(journal.lock(|journal| read_boot_history(&mut journal.borrow_mut())))
+}
+
+#[derive(Serialize)]
+pub struct SlotStatus {
+    name: &'static str,
+    state: Option<SlotState>,
+}
+
+#[derive(Serialize)]
+pub struct SystemResponse {
+    version: &'static str,
+    build_time: &'static str,
+    booted_slot: Option<SlotStatus>,
+    /// Slot the next update is written to
+    next_slot: Option<SlotStatus>,
+    uptime_secs: u64,
+    heap_used: usize,
+    heap_free: usize,
+    reset_reason: Option<heapless::String<32>>,
+    /// Signal strength in dBm, when connected to a network
+    rssi: Option<i32>,
+}
+
+pub fn get_system(provisioning: &WifiProvisioning) -> picoserve::response::Json<SystemResponse> {
+    const SLOTS: [&str; 2] = ["ota_0", "ota_1"];
+    let booted = get_booted_partition(&mut FlashStorage::new())
+        .ok()
+        .and_then(|partition| SLOTS.iter().position(|&slot| slot == partition.name()));
+    let states = OtaData::open()
+        .and_then(|mut ota_data| ota_data.slot_states())
+        .unwrap_or_default();
+    let slot = |index: usize| SlotStatus {
+        name: SLOTS[index],
+        state: states[index],
+    };
+
+    picoserve::response::Json(SystemResponse {
+        version: ESP_APP_DESC.version(),
+        build_time: build_time_local!("%Y-%m-%dT%H:%M:%S%:z"),
+        booted_slot: booted.map(slot),
+        next_slot: booted.map(|index| slot(1 - index)),
+        uptime_secs: Instant::now().as_secs(),
+        heap_used: esp_alloc::HEAP.used(),
+        heap_free: esp_alloc::HEAP.free(),
+        reset_reason: reset_reason(Cpu::ProCpu).map(|reason| format(format_args!("{reason:?}"))),
+        rssi: provisioning.rssi(),
+    })
+}
+
+pub fn get_storage(journal: &SharedJournal) -> picoserve::response::Json<StorageStatus> {
+    picoserve::response::Json(journal.lock(|journal| journal.borrow().status()))
+}
@@ -0,0 +1,320 @@
+use crate::journal::{store_record, Journal, RecordKind, SharedJournal};
+use base64::Engine;
+use core::cell::RefCell;
+use embassy_executor::Spawner;
+use embassy_futures::select::{select, Either};
+use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_time::{Duration, Timer};
+use embedded_io_async::Read;
+use esp_hal::gpio::{Input, InputConfig, Pull};
+use esp_hal::peripherals::GPIO9;
+use esp_hal::rng::Rng;
+use picoserve::request::RequestParts;
+use picoserve::response::{IntoResponse, ResponseWriter, StatusCode};
+use picoserve::routing::{Layer, Next};
+use picoserve::ResponseSent;
+use sha2::{Digest, Sha256};
+
+const HASH_LEN: usize = 32;
+const SALT_LEN: usize = 16;
+/// `[SALT: 16, HASH: 32]`
+const PASSWORD_LEN: usize = SALT_LEN + HASH_LEN;
+const AUTH_CONFIG_LEN: usize = 1 + 2 * PASSWORD_LEN;
+const RECORD_VERSION: u8 = 1;
+/// How long the boot button has to be held to remove the passwords
+const RESET_HOLD: Duration = Duration::from_secs(5);
+/// Longest decoded `username:password` that is accepted
+const MAX_CREDENTIALS_LEN: usize = 96;
+
+pub const USER_NAME: &str = "user";
+pub const ADMIN_NAME: &str = "admin";
+
+type PasswordHash = [u8; HASH_LEN];
+type Salt = [u8; SALT_LEN];
+
+pub type SharedAuthConfig = Mutex<NoopRawMutex, RefCell<AuthConfig>>;
+
+/// Everyday colour control needs `User`, OTA updates and configuration need `Admin`
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum AccessLevel {
+    User,
+    Admin,
+}
+
+/// A password hash with the salt it was made with
+#[derive(Clone, Debug)]
+struct StoredPassword {
+    salt: Salt,
+    hash: PasswordHash,
+}
+
+impl StoredPassword {
+    fn new(password: &str, mut rng: Rng) -> Self {
+        let mut salt = [0; SALT_LEN];
+        rng.read(&mut salt);
+        Self {
+            hash: hash_password(&salt, password),
+            salt,
+        }
+    }
+
+    /// Matches no password, for records that can't be read
+    fn locked() -> Self {
+        Self {
+            salt: [0; SALT_LEN],
+            hash: [0xFF; HASH_LEN],
+        }
+    }
+
+    fn matches(&self, password: &str) -> bool {
+        hashes_equal(&hash_password(&self.salt, password), &self.hash)
+    }
+
+    /// Reads `[SALT: 16, HASH: 32]`
+    fn from_bytes(bytes: &[u8; PASSWORD_LEN]) -> Self {
+        Self {
+            salt: bytes[..SALT_LEN].try_into().unwrap(),
+            hash: bytes[SALT_LEN..].try_into().unwrap(),
+        }
+    }
+
+    fn write_to(&self, bytes: &mut [u8]) {
+        bytes[..SALT_LEN].copy_from_slice(&self.salt);
+        bytes[SALT_LEN..PASSWORD_LEN].copy_from_slice(&self.hash);
+    }
+}
+
+/// Hashes of the passwords, a level without password is open.
+/// When only the user password is set, it also grants admin access.
+///
+/// The passwords are sent with HTTP Basic authentication over plain HTTP, so they only keep
+/// out other users of the local network that don't capture its traffic.
+#[derive(Clone, Debug, Default)]
+pub struct AuthConfig {
+    user: Option<StoredPassword>,
+    admin: Option<StoredPassword>,
+}
+
+impl AuthConfig {
+    /// Reads `[FLAGS, USER_PASSWORD: 48, ADMIN_PASSWORD: 48]`, where the flags mark which
+    /// passwords are set
+    pub fn from_bytes(bytes: &[u8; AUTH_CONFIG_LEN]) -> Self {
+        let password = |flag: u8, start: usize| {
+            (bytes[0] & flag != 0).then(|| {
+                StoredPassword::from_bytes(bytes[start..start + PASSWORD_LEN].try_into().unwrap())
+            })
+        };
+        Self {
+            user: password(0b01, 1),
+            admin: password(0b10, 1 + PASSWORD_LEN),
+        }
+    }
+
+    pub fn into_bytes(self) -> [u8; AUTH_CONFIG_LEN] {
+        let mut bytes = [0; AUTH_CONFIG_LEN];
+        bytes[0] = self.user.is_some() as u8 | (self.admin.is_some() as u8) << 1;
+        if let Some(user) = &self.user {
+            user.write_to(&mut bytes[1..1 + PASSWORD_LEN]);
+        }
+        if let Some(admin) = &self.admin {
+            admin.write_to(&mut bytes[1 + PASSWORD_LEN..]);
+        }
+        bytes
+    }
+
+    pub fn has_password(&self, level: AccessLevel) -> bool {
+        match level {
+            AccessLevel::User => self.user.is_some(),
+            AccessLevel::Admin => self.admin.is_some(),
+        }
+    }
+
+    /// Sets the password of a level with a new salt from `rng`, an empty password removes it
+    pub fn set_password(&mut self, level: AccessLevel, password: &str, rng: Rng) {
+        let stored = (!password.is_empty()).then(|| StoredPassword::new(password, rng));
+        match level {
+            AccessLevel::User => self.user = stored,
+            AccessLevel::Admin => self.admin = stored,
+        }
+    }
+
+    /// Whether the credentials from a request give access to `required`
+    pub fn allows(&self, required: AccessLevel, credentials: Option<(&str, &str)>) -> bool {
+        let matches = |name: &str, stored: &Option<StoredPassword>| match (credentials, stored) {
+            (Some((username, password)), Some(stored)) => {
+                username == name && stored.matches(password)
+            }
+            _ => false,
+        };
+        let is_user = matches(USER_NAME, &self.user);
+        let is_admin = matches(ADMIN_NAME, &self.admin);
+        match required {
+            AccessLevel::User => self.user.is_none() || is_user || is_admin,
+            AccessLevel::Admin if self.admin.is_some() => is_admin,
+            AccessLevel::Admin => self.user.is_none() || is_user,
+        }
+    }
+}
+
+/// Salts the password, so equal passwords have different hashes
+fn hash_password(salt: &[u8], password: &str) -> PasswordHash {
+    let mut hasher = Sha256::new();
+    hasher.update(salt);
+    hasher.update(password.as_bytes());
+    hasher.finalize().into()
+}
+
+/// Compares without stopping at the first difference, so the timing doesn't reveal the hash
+fn hashes_equal(a: &PasswordHash, b: &PasswordHash) -> bool {
+    a.iter().zip(b).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+pub fn read_auth_config(journal: &mut Journal) -> AuthConfig {
+    let mut buffer = [0; AUTH_CONFIG_LEN];
+    let config = match journal.read_latest(RecordKind::AuthConfig, &mut buffer) {
+        None => return AuthConfig::default(),
+        Some((RECORD_VERSION, AUTH_CONFIG_LEN)) => Some(AuthConfig::from_bytes(&buffer)),
+        Some((version, len)) => {
+            log::warn!("Invalid auth config record version {version} with length {len}");
+            None
+        }
+    };
+    // Failing open would let anyone in, so lock everything until the passwords are reset with
+    // the boot button
+    config.unwrap_or_else(|| AuthConfig {
+        user: Some(StoredPassword::locked()),
+        admin: Some(StoredPassword::locked()),
+    })
+}
+
+pub fn store_auth_config(journal: &SharedJournal, config: AuthConfig) -> bool {
+    let stored = store_record(
+        journal,
+        RecordKind::AuthConfig,
+        RECORD_VERSION,
+        &config.into_bytes(),
+    );
+    if stored {
+        log::info!("Passwords updated");
+    }
+    stored
+}
+
+/// Watches the boot button, which removes the passwords when held
+pub fn setup_auth_reset(
+    button: GPIO9<'static>,
+    auth: &'static SharedAuthConfig,
+    journal: &'static SharedJournal,
+    spawner: Spawner,
+) {
+    let button = Input::new(button, InputConfig::default().with_pull(Pull::Up));
+    spawner.must_spawn(auth_reset_task(button, auth, journal));
+}
+
+/// Holding the button while powering on starts the download mode of the ROM instead, so it's
+/// only watched once the lamp runs
+#[embassy_executor::task]
+async fn auth_reset_task(
+    mut button: Input<'static>,
+    auth: &'static SharedAuthConfig,
+    journal: &'static SharedJournal,
+) -> ! {
+    loop {
+        button.wait_for_low().await;
+        if let Either::Second(()) = select(button.wait_for_high(), Timer::after(RESET_HOLD)).await {
+            log::warn!("Boot button held, removing the passwords");
+            auth.lock(|auth| auth.replace(AuthConfig::default()));
+            store_auth_config(journal, AuthConfig::default());
+            button.wait_for_high().await;
+        }
+    }
+}
+
+/// The access level a request needs
+fn required_level(method: &str, path: &str) -> Option<AccessLevel> {
+    match (method, path) {
+        // Connectivity checks of the captive portal only redirect
+        (
+            _,
+            "/generate_204"
+            | "/gen_204"
+            | "/hotspot-detect.html"
+            | "/connecttest.txt"
+            | "/ncsi.txt",
+        ) => None,
+        (
+            _,
+            "/"
+            | "/style.css"
+            | "/ws"
+            | "/ws/v2"
+            | "/api/state"
+            | "/api/presets"
+            | "/api/presets/recall",
+        ) => Some(AccessLevel::User),
+        // The control page shows the name of the lamp
+        ("GET", "/api/device") => Some(AccessLevel::User),
+        _ => Some(AccessLevel::Admin),
+    }
+}
+
+/// Parses a `Basic` authorization header into the username and password
+fn decode_basic<'a>(
+    header: &[u8],
+    buffer: &'a mut [u8; MAX_CREDENTIALS_LEN],
+) -> Option<(&'a str, &'a str)> {
+    let encoded = header.strip_prefix(b"Basic ")?;
+    let len = base64::engine::general_purpose::STANDARD
+        .decode_slice(encoded, buffer)
+        .ok()?;
+    core::str::from_utf8(&buffer[..len]).ok()?.split_once(':')
+}
+
+/// Checks HTTP Basic credentials against the access level of the requested route
+pub struct AuthLayer {
+    pub auth: &'static SharedAuthConfig,
+}
+
+impl<State, PathParameters> Layer<State, PathParameters> for AuthLayer {
+    type NextState = State;
+    type NextPathParameters = PathParameters;
+
+    async fn call_layer<
+        'a,
+        R: Read + 'a,
+        NextLayer: Next<'a, R, Self::NextState, Self::NextPathParameters>,
+        W: ResponseWriter<Error = R::Error>,
+    >(
+        &self,
+        next: NextLayer,
+        state: &State,
+        path_parameters: PathParameters,
+        request_parts: RequestParts<'_>,
+        response_writer: W,
+    ) -> Result<ResponseSent, W::Error> {
+        let mut buffer = [0; MAX_CREDENTIALS_LEN];
+        let credentials = request_parts
+            .headers()
+            .get("Authorization")
+            .and_then(|value| decode_basic(value.as_raw(), &mut buffer));
+        let allowed = match required_level(request_parts.method(), request_parts.path().encoded()) {
+            None => true,
+            Some(level) => self
+                .auth
+                .lock(|auth| auth.borrow().allows(level, credentials)),
+        };
+        if allowed {
+            return next.run(state, path_parameters, response_writer).await;
+        }
+
+        let connection = next.into_connection().await?;
+        (
+            StatusCode::UNAUTHORIZED,
+            [("WWW-Authenticate", "Basic realm=\"Lightbringer\"")],
+            "Unauthorized\n",
+        )
+            .write_to(connection, response_writer)
+            .await
+    }
+}
@@ -0,0 +1,90 @@
+use crate::dhcp_server::dhcp_server_task;
+use crate::dns::{
+    read_question, read_u16, write_u16, CLASS_IN, FLAG_AUTHORITATIVE, FLAG_RESPONSE, HEADER_LEN,
+    TYPE_A,
+};
+use embassy_executor::Spawner;
+use embassy_net::udp::{PacketMetadata, UdpSocket};
+use embassy_net::{Ipv4Address, Stack};
+
+/// Address of the lamp on its own access point
+pub const PORTAL_ADDRESS: Ipv4Address = Ipv4Address::new(192, 168, 4, 1);
+pub const PORTAL_PREFIX_LEN: u8 = 24;
+
+const DNS_PORT: u16 = 53;
+const DNS_TTL: u32 = 60;
+const MAX_PACKET_LEN: usize = 512;
+
+/// Starts the services that make clients of the access point open the provisioning page
+pub fn setup_captive_portal(ap_stack: Stack<'static>, spawner: Spawner) {
+    spawner.must_spawn(dhcp_server_task(ap_stack));
+    spawner.must_spawn(dns_task(ap_stack));
+}
+
+/// Answers every DNS query with the address of the lamp
+#[embassy_executor::task]
+async fn dns_task(stack: Stack<'static>) -> ! {
+    let mut rx_meta = [PacketMetadata::EMPTY; 4];
+    let mut rx_buffer = [0; MAX_PACKET_LEN];
+    let mut tx_meta = [PacketMetadata::EMPTY; 4];
+    let mut tx_buffer = [0; MAX_PACKET_LEN];
+    let mut socket = UdpSocket::new(
+        stack,
+        &mut rx_meta,
+        &mut rx_buffer,
+        &mut tx_meta,
+        &mut tx_buffer,
+    );
+    socket.bind(DNS_PORT).unwrap();
+
+    let mut packet = [0; MAX_PACKET_LEN];
+    loop {
+        let Ok((len, meta)) = socket.recv_from(&mut packet).await else {
+            continue;
+        };
+        if let Some(response_len) = answer_query(&mut packet, len) {
+            socket
+                .send_to(&packet[..response_len], meta.endpoint)
+                .await
+                .ok();
+        }
+    }
+}
+
+/// Turns the query in `packet` into a response, returning its length
+fn answer_query(packet: &mut [u8], len: usize) -> Option<usize> {
+    let flags = read_u16(&packet[..len], 2)?;
+    if flags & FLAG_RESPONSE != 0 || read_u16(&packet[..len], 4)? == 0 {
+        return None;
+    }
+    // Only answer the first question
+    let question = read_question(&packet[..len], HEADER_LEN)?;
+
+    // Keep the opcode and recursion desired bits
+    write_u16(
+        packet,
+        2,
+        flags & 0x7900 | FLAG_RESPONSE | FLAG_AUTHORITATIVE,
+    );
+    write_u16(packet, 4, 1);
+    write_u16(packet, 8, 0);
+    write_u16(packet, 10, 0);
+    if question.qtype != TYPE_A || question.qclass != CLASS_IN {
+        write_u16(packet, 6, 0);
+        return Some(question.end);
+    }
+    write_u16(packet, 6, 1);
+
+    let mut answer = [0; 16];
+    // Pointer to the name in the question
+    answer[..2].copy_from_slice(&(0xC000 | HEADER_LEN as u16).to_be_bytes());
+    answer[2..4].copy_from_slice(&TYPE_A.to_be_bytes());
+    answer[4..6].copy_from_slice(&CLASS_IN.to_be_bytes());
+    answer[6..10].copy_from_slice(&DNS_TTL.to_be_bytes());
+    answer[10..12].copy_from_slice(&4u16.to_be_bytes());
+    answer[12..].copy_from_slice(&PORTAL_ADDRESS.octets());
+
+    let end = question.end + answer.len();
+    packet.get_mut(question.end..end)?.copy_from_slice(&answer);
+    Some(end)
+}
@@ -1,55 +1,140 @@
+use crate::dimming_curve::DimmingCurve;
 use crate::http::MAX_LISTENERS;
-use crate::light_state::{LightState, LIGHT_STATE_LEN};
+use crate::journal::{Journal, RecordKind, SharedJournal};
+use crate::light_state::{LightState, CHANNEL_STATE_LEN, LIGHT_STATE_LEN};
+use crate::make_static;
+use crate::partitions::Partition;
 use crate::value_synchronizer::ValueSynchronizer;
 use embassy_executor::Spawner;
+use embassy_futures::select::{select, Either};
 use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+use embassy_sync::signal::Signal;
 use embassy_time::Timer;
-use embedded_storage::{ReadStorage, Storage};
-use esp_ota_nostd::partitions::find_partition_by_name;
-use esp_storage::FlashStorage;
 
 const WRITE_DELAY: u64 = 5;
+/// Failed writes are retried with a doubling delay between these bounds
+const RETRY_MIN_SECS: u64 = 1;
+const RETRY_MAX_SECS: u64 = 60;
 
-pub fn read_light_state() -> LightState {
-    let mut flash = FlashStorage::new();
-    let partition = find_partition_by_name(&mut flash, "userdata").unwrap();
+/// Signalled when the current light state should be written to flash
+pub type SaveSignal = Signal<NoopRawMutex, ()>;
 
+/// Version of the light state record payload written by this firmware
+const RECORD_VERSION: u8 = 2;
+
+/// Firmware from before the journal wrote the bare light state to the start of this partition
+const LEGACY_PARTITION: &str = "userdata";
+const LEGACY_READ_LEN: usize = 16;
+
+/// Channel values of the legacy state are converted using the dimming curve
+pub fn read_light_state(journal: &mut Journal, curve: &DimmingCurve) -> LightState {
     let mut buffer = [0; LIGHT_STATE_LEN];
-    flash.read(partition.offset, &mut buffer).unwrap();
+    if let Some((version, len)) = journal.read_latest(RecordKind::LightState, &mut buffer) {
+        if let Some(state) = decode_payload(version, &buffer[..len]) {
+            return state;
+        }
+        log::warn!("Stored light state is invalid, falling back to default.");
+        return LightState::default();
+    }
 
-    // Uninitialized
-    if buffer.iter().all(|v| *v == 255) {
+    let Some(state) = read_legacy_light_state(curve) else {
         log::info!("Initializing to first-time light state.");
         return LightState::default();
+    };
+    // Store it before anything else is written, so the journal holds it from now on
+    if let Err(e) = journal.append(RecordKind::LightState, RECORD_VERSION, &state.into_bytes()) {
+        log::warn!("Storing migrated light state failed: {e:?}");
     }
+    state
+}
 
-    LightState::from_bytes(&buffer)
+fn decode_payload(version: u8, payload: &[u8]) -> Option<LightState> {
+    match version {
+        2 => Some(LightState::from_bytes(payload.try_into().ok()?)),
+        _ => {
+            log::warn!("Unknown light state record version {version}");
+            None
+        }
+    }
+}
+
+/// Reads the light state stored by firmware from before the journal was introduced
+fn read_legacy_light_state(curve: &DimmingCurve) -> Option<LightState> {
+    let mut partition = Partition::open(LEGACY_PARTITION).ok()?;
+    let mut buffer = [0; LEGACY_READ_LEN];
+    partition.read(0, &mut buffer).ok()?;
+
+    // Uninitialized
+    if buffer.iter().all(|v| *v == 255) {
+        return None;
+    }
+
+    // Nothing was written behind the light state
+    if buffer[CHANNEL_STATE_LEN..].iter().any(|v| *v != 255) {
+        log::warn!("Unrecognized data in {LEGACY_PARTITION} partition.");
+        return None;
+    }
+    log::info!("Migrating light state from {LEGACY_PARTITION} partition.");
+    Some(LightState::from_channel_bytes(
+        buffer[..CHANNEL_STATE_LEN].try_into().unwrap(),
+        curve,
+    ))
 }
 
 pub fn setup_color_storage(
     spawner: Spawner,
     value: &'static ValueSynchronizer<MAX_LISTENERS, NoopRawMutex, LightState>,
-) {
-    spawner.must_spawn(storage_task(value));
+    journal: &'static SharedJournal,
+) -> &'static SaveSignal {
+    let save = make_static!(SaveSignal, Signal::new());
+    spawner.must_spawn(storage_task(value, save, journal));
+    save
 }
 
 #[embassy_executor::task]
 async fn storage_task(
     value: &'static ValueSynchronizer<MAX_LISTENERS, NoopRawMutex, LightState>,
+    save: &'static SaveSignal,
+    journal: &'static SharedJournal,
 ) -> ! {
-    let mut flash = FlashStorage::new();
-    let mut watcher = value.watch();
-    let partition = find_partition_by_name(&mut flash, "userdata").unwrap();
     loop {
-        watcher.read().await;
+        save.wait().await;
 
+        // Save requests in quick succession only lead to a single write
         Timer::after_secs(WRITE_DELAY).await;
 
-        watcher.skip().await;
-        let message = value.read_clone();
-        flash
-            .write(partition.offset, &message.into_bytes())
-            .unwrap();
-        log::info!("Flash storage updated");
+        save.reset();
+        let mut backoff = RETRY_MIN_SECS;
+        loop {
+            // A retry stores the newest state, which may have changed in the meantime
+            let message = value.read_clone();
+            let (result, available) = journal.lock(|journal| {
+                let mut journal = journal.borrow_mut();
+                let result = journal.append(
+                    RecordKind::LightState,
+                    RECORD_VERSION,
+                    &message.into_bytes(),
+                );
+                (result, journal.status().available)
+            });
+            match result {
+                Ok(()) => {
+                    log::info!("Flash storage updated");
+                    break;
+                }
+                Err(e) if !available => {
+                    log::warn!("Saving light state failed, keeping it in memory: {e:?}");
+                    break;
+                }
+                Err(e) => log::warn!("Saving light state failed, retrying in {backoff}s: {e:?}"),
+            }
+
+            // A new save request restarts the write delay
+            if let Either::Second(()) = select(Timer::after_secs(backoff), save.wait()).await {
+                save.signal(());
+                break;
+            }
+            backoff = (backoff * 2).min(RETRY_MAX_SECS);
+        }
     }
 }
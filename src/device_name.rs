@@ -0,0 +1,58 @@
+use crate::journal::{store_record, Journal, RecordKind, SharedJournal};
+use crate::value_synchronizer::ValueSynchronizer;
+use core::fmt::Write;
+use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+use heapless::String;
+
+/// Also the longest hostname the DHCP client can send
+pub const MAX_DEVICE_NAME_LEN: usize = 32;
+const RECORD_VERSION: u8 = 1;
+
+/// Name of the lamp, which is also its hostname
+pub type DeviceName = String<MAX_DEVICE_NAME_LEN>;
+
+/// The name is watched by the DHCP client, the mDNS responder and the MQTT client
+pub type SharedDeviceName = ValueSynchronizer<3, NoopRawMutex, DeviceName>;
+
+/// Names must be valid hostnames: letters, digits and dashes, not starting or ending with a dash
+pub fn is_valid_device_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= MAX_DEVICE_NAME_LEN
+        && !name.starts_with('-')
+        && !name.ends_with('-')
+        && name.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-')
+}
+
+/// Name based on the MAC address, so lamps on the same network can be told apart
+pub fn default_device_name(mac: [u8; 6]) -> DeviceName {
+    let mut name = String::new();
+    write!(name, "lightbringer-{:02x}{:02x}", mac[4], mac[5]).unwrap();
+    name
+}
+
+pub fn read_device_name(journal: &mut Journal) -> Option<DeviceName> {
+    let mut buffer = [0; MAX_DEVICE_NAME_LEN];
+    match journal.read_latest(RecordKind::DeviceName, &mut buffer)? {
+        (RECORD_VERSION, len) => {
+            let name = core::str::from_utf8(&buffer[..len]).ok()?;
+            is_valid_device_name(name).then(|| name.try_into().unwrap())
+        }
+        (version, _) => {
+            log::warn!("Unknown device name record version {version}");
+            None
+        }
+    }
+}
+
+pub fn store_device_name(journal: &SharedJournal, name: &str) -> bool {
+    let stored = store_record(
+        journal,
+        RecordKind::DeviceName,
+        RECORD_VERSION,
+        name.as_bytes(),
+    );
+    if stored {
+        log::info!("Device name changed to {name}");
+    }
+    stored
+}
@@ -0,0 +1,166 @@
+use crate::captive_portal::{PORTAL_ADDRESS, PORTAL_PREFIX_LEN};
+use embassy_net::udp::{PacketMetadata, UdpSocket};
+use embassy_net::{IpEndpoint, Ipv4Address, Stack};
+use embassy_time::Instant;
+
+const SERVER_PORT: u16 = 67;
+const CLIENT_PORT: u16 = 68;
+const MAX_PACKET_LEN: usize = 576;
+
+/// Addresses handed out are `192.168.4.2` and up
+const POOL_START: u8 = 2;
+const POOL_SIZE: usize = 8;
+const LEASE_TIME_SECS: u32 = 3600;
+
+// Offsets in a BOOTP message
+const OP: usize = 0;
+const HOPS: usize = 3;
+const CIADDR: usize = 12;
+const YIADDR: usize = 16;
+const SIADDR: usize = 20;
+const GIADDR: usize = 24;
+const CHADDR: usize = 28;
+const SNAME: usize = 44;
+const MAGIC: usize = 236;
+const OPTIONS: usize = 240;
+const MAGIC_COOKIE: [u8; 4] = [99, 130, 83, 99];
+
+const OP_REQUEST: u8 = 1;
+const OP_REPLY: u8 = 2;
+
+const OPTION_SUBNET_MASK: u8 = 1;
+const OPTION_ROUTER: u8 = 3;
+const OPTION_DNS: u8 = 6;
+const OPTION_LEASE_TIME: u8 = 51;
+const OPTION_MESSAGE_TYPE: u8 = 53;
+const OPTION_SERVER_ID: u8 = 54;
+const OPTION_END: u8 = 255;
+const OPTION_PAD: u8 = 0;
+
+const DISCOVER: u8 = 1;
+const OFFER: u8 = 2;
+const REQUEST: u8 = 3;
+const ACK: u8 = 5;
+
+/// Minimal DHCP server for clients of the access point, which hands out addresses by MAC
+#[embassy_executor::task]
+pub async fn dhcp_server_task(stack: Stack<'static>) -> ! {
+    let mut rx_meta = [PacketMetadata::EMPTY; 4];
+    let mut rx_buffer = [0; MAX_PACKET_LEN];
+    let mut tx_meta = [PacketMetadata::EMPTY; 4];
+    let mut tx_buffer = [0; MAX_PACKET_LEN];
+    let mut socket = UdpSocket::new(
+        stack,
+        &mut rx_meta,
+        &mut rx_buffer,
+        &mut tx_meta,
+        &mut tx_buffer,
+    );
+    socket.bind(SERVER_PORT).unwrap();
+
+    let mut leases = Leases::default();
+    let mut packet = [0; MAX_PACKET_LEN];
+    let broadcast = IpEndpoint::new(Ipv4Address::BROADCAST.into(), CLIENT_PORT);
+    loop {
+        let Ok((len, _)) = socket.recv_from(&mut packet).await else {
+            continue;
+        };
+        if let Some(reply_len) = handle_message(&mut packet, len, &mut leases) {
+            socket.send_to(&packet[..reply_len], broadcast).await.ok();
+        }
+    }
+}
+
+#[derive(Copy, Clone)]
+struct Lease {
+    mac: [u8; 6],
+    seen: Instant,
+}
+
+#[derive(Default)]
+struct Leases([Option<Lease>; POOL_SIZE]);
+
+impl Leases {
+    /// Address for a client, taking over the least recently seen lease when the pool is exhausted
+    fn address_for(&mut self, mac: [u8; 6], now: Instant) -> Ipv4Address {
+        let known = self
+            .0
+            .iter()
+            .position(|lease| lease.is_some_and(|l| l.mac == mac));
+        let index = known
+            .or_else(|| self.0.iter().position(Option::is_none))
+            .unwrap_or_else(|| {
+                (0..POOL_SIZE)
+                    .min_by_key(|&i| self.0[i].map(|l| l.seen))
+                    .unwrap_or(0)
+            });
+        self.0[index] = Some(Lease { mac, seen: now });
+        let [a, b, c, _] = PORTAL_ADDRESS.octets();
+        Ipv4Address::new(a, b, c, POOL_START + index as u8)
+    }
+}
+
+fn message_type(options: &[u8]) -> Option<u8> {
+    let mut pos = 0;
+    loop {
+        match *options.get(pos)? {
+            OPTION_END => return None,
+            OPTION_PAD => pos += 1,
+            option => {
+                let len = *options.get(pos + 1)? as usize;
+                if option == OPTION_MESSAGE_TYPE && len == 1 {
+                    return options.get(pos + 2).copied();
+                }
+                pos += 2 + len;
+            }
+        }
+    }
+}
+
+/// Turns a discover or request in `packet` into an offer or ack, returning its length
+fn handle_message(packet: &mut [u8], len: usize, leases: &mut Leases) -> Option<usize> {
+    if len < OPTIONS || packet[OP] != OP_REQUEST || packet[MAGIC..OPTIONS] != MAGIC_COOKIE {
+        return None;
+    }
+    let reply_type = match message_type(&packet[OPTIONS..len])? {
+        DISCOVER => OFFER,
+        REQUEST => ACK,
+        _ => return None,
+    };
+    let mac: [u8; 6] = packet[CHADDR..CHADDR + 6].try_into().unwrap();
+    let address = leases.address_for(mac, Instant::now());
+    log::info!("Provisioning client {mac:02x?} gets address {address}");
+
+    // Keep the transaction id, flags and client hardware address of the request
+    packet[OP] = OP_REPLY;
+    packet[HOPS] = 0;
+    packet[CIADDR..YIADDR].fill(0);
+    packet[YIADDR..SIADDR].copy_from_slice(&address.octets());
+    packet[SIADDR..GIADDR].copy_from_slice(&PORTAL_ADDRESS.octets());
+    packet[GIADDR..CHADDR].fill(0);
+    packet[SNAME..MAGIC].fill(0);
+
+    let mask = u32::MAX << (32 - PORTAL_PREFIX_LEN);
+    let server = PORTAL_ADDRESS.octets();
+    let mut options = heapless::Vec::<u8, 64>::new();
+    options
+        .extend_from_slice(&[OPTION_MESSAGE_TYPE, 1, reply_type])
+        .ok()?;
+    options.extend_from_slice(&[OPTION_SERVER_ID, 4]).ok()?;
+    options.extend_from_slice(&server).ok()?;
+    options.extend_from_slice(&[OPTION_LEASE_TIME, 4]).ok()?;
+    options
+        .extend_from_slice(&LEASE_TIME_SECS.to_be_bytes())
+        .ok()?;
+    options.extend_from_slice(&[OPTION_SUBNET_MASK, 4]).ok()?;
+    options.extend_from_slice(&mask.to_be_bytes()).ok()?;
+    options.extend_from_slice(&[OPTION_ROUTER, 4]).ok()?;
+    options.extend_from_slice(&server).ok()?;
+    options.extend_from_slice(&[OPTION_DNS, 4]).ok()?;
+    options.extend_from_slice(&server).ok()?;
+    options.push(OPTION_END).ok()?;
+
+    let end = OPTIONS + options.len();
+    packet.get_mut(OPTIONS..end)?.copy_from_slice(&options);
+    Some(end)
+}
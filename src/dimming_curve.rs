@@ -0,0 +1,38 @@
+use crate::journal::{store_record, Journal, RecordKind, SharedJournal};
+use crate::value_synchronizer::ValueSynchronizer;
+use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+pub use lightbringer_core::dimming_curve::{DimmingCurve, CURVE_POINTS, DIMMING_CURVE_LEN};
+
+const RECORD_VERSION: u8 = 1;
+
+/// The curve is only watched by the led task
+pub type SharedDimmingCurve = ValueSynchronizer<1, NoopRawMutex, DimmingCurve>;
+
+pub fn read_dimming_curve(journal: &mut Journal) -> DimmingCurve {
+    let mut buffer = [0; DIMMING_CURVE_LEN];
+    match journal.read_latest(RecordKind::DimmingCurve, &mut buffer) {
+        Some((RECORD_VERSION, DIMMING_CURVE_LEN)) => DimmingCurve::from_bytes(&buffer)
+            .unwrap_or_else(|| {
+                log::warn!("Stored dimming curve is invalid, using default.");
+                DimmingCurve::default()
+            }),
+        Some((version, len)) => {
+            log::warn!("Unknown dimming curve record version {version} with length {len}");
+            DimmingCurve::default()
+        }
+        None => DimmingCurve::default(),
+    }
+}
+
+pub fn store_dimming_curve(journal: &SharedJournal, curve: DimmingCurve) -> bool {
+    let stored = store_record(
+        journal,
+        RecordKind::DimmingCurve,
+        RECORD_VERSION,
+        &curve.into_bytes(),
+    );
+    if stored {
+        log::info!("Dimming curve updated");
+    }
+    stored
+}
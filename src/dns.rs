@@ -0,0 +1,150 @@
+pub const HEADER_LEN: usize = 12;
+pub const TYPE_A: u16 = 1;
+pub const TYPE_PTR: u16 = 12;
+pub const TYPE_TXT: u16 = 16;
+pub const TYPE_AAAA: u16 = 28;
+pub const TYPE_SRV: u16 = 33;
+pub const TYPE_ANY: u16 = 255;
+pub const CLASS_IN: u16 = 1;
+
+/// Flag that marks a packet as response
+pub const FLAG_RESPONSE: u16 = 0x8000;
+/// Flag that marks a response as authoritative
+pub const FLAG_AUTHORITATIVE: u16 = 0x0400;
+
+pub fn read_u16(packet: &[u8], pos: usize) -> Option<u16> {
+    Some(u16::from_be_bytes([
+        *packet.get(pos)?,
+        *packet.get(pos + 1)?,
+    ]))
+}
+
+pub fn write_u16(packet: &mut [u8], pos: usize, value: u16) {
+    packet[pos..pos + 2].copy_from_slice(&value.to_be_bytes());
+}
+
+/// A question in a received packet
+#[derive(Copy, Clone, Debug)]
+pub struct Question {
+    /// Position of the name in the packet
+    pub name: usize,
+    pub qtype: u16,
+    pub qclass: u16,
+    /// Position after the question
+    pub end: usize,
+}
+
+/// Returns the position after the name that starts at `pos`
+pub fn skip_name(packet: &[u8], mut pos: usize) -> Option<usize> {
+    loop {
+        let len = *packet.get(pos)? as usize;
+        match len {
+            0 => return Some(pos + 1),
+            // A compression pointer always ends the name
+            _ if len & 0xC0 == 0xC0 => return Some(pos + 2),
+            _ => pos += 1 + len,
+        }
+    }
+}
+
+pub fn read_question(packet: &[u8], pos: usize) -> Option<Question> {
+    let name_end = skip_name(packet, pos)?;
+    Some(Question {
+        name: pos,
+        qtype: read_u16(packet, name_end)?,
+        qclass: read_u16(packet, name_end + 2)?,
+        end: name_end + 4,
+    })
+}
+
+/// Reads the (possibly compressed) name at `pos` in lowercase dotted form, without trailing dot
+pub fn read_name<const N: usize>(packet: &[u8], mut pos: usize) -> Option<heapless::String<N>> {
+    let mut name = heapless::String::new();
+    // Bound the number of pointers followed, so a malicious packet can't make us loop forever
+    let mut jumps = 0;
+    loop {
+        let len = *packet.get(pos)? as usize;
+        match len {
+            0 => return Some(name),
+            _ if len & 0xC0 == 0xC0 => {
+                jumps += 1;
+                if jumps > 8 {
+                    return None;
+                }
+                pos = read_u16(packet, pos)? as usize & 0x3FFF;
+            }
+            _ => {
+                let label = core::str::from_utf8(packet.get(pos + 1..pos + 1 + len)?).ok()?;
+                if !name.is_empty() {
+                    name.push('.').ok()?;
+                }
+                for c in label.chars() {
+                    name.push(c.to_ascii_lowercase()).ok()?;
+                }
+                pos += 1 + len;
+            }
+        }
+    }
+}
+
+/// Writes a DNS message into a buffer, failing when it doesn't fit
+pub struct Writer<'a> {
+    buffer: &'a mut [u8],
+    len: usize,
+}
+
+impl<'a> Writer<'a> {
+    pub fn new(buffer: &'a mut [u8]) -> Self {
+        Self { buffer, len: 0 }
+    }
+
+    /// Length of the message written so far
+    pub fn position(&self) -> usize {
+        self.len
+    }
+
+    pub fn bytes(&mut self, bytes: &[u8]) -> Option<()> {
+        let end = self.len + bytes.len();
+        self.buffer.get_mut(self.len..end)?.copy_from_slice(bytes);
+        self.len = end;
+        Some(())
+    }
+
+    pub fn u16(&mut self, value: u16) -> Option<()> {
+        self.bytes(&value.to_be_bytes())
+    }
+
+    pub fn u32(&mut self, value: u32) -> Option<()> {
+        self.bytes(&value.to_be_bytes())
+    }
+
+    /// Writes an uncompressed name given by its dot separated labels
+    pub fn name(&mut self, labels: &[&str]) -> Option<()> {
+        for label in labels.iter().flat_map(|l| l.split('.')) {
+            self.bytes(&[label.len() as u8])?;
+            self.bytes(label.as_bytes())?;
+        }
+        self.bytes(&[0])
+    }
+
+    /// Writes a resource record, where `data` writes its contents
+    pub fn record(
+        &mut self,
+        name: &[&str],
+        rtype: u16,
+        class: u16,
+        ttl: u32,
+        data: impl FnOnce(&mut Self) -> Option<()>,
+    ) -> Option<()> {
+        self.name(name)?;
+        self.u16(rtype)?;
+        self.u16(class)?;
+        self.u32(ttl)?;
+        let len_pos = self.len;
+        self.u16(0)?;
+        data(self)?;
+        let data_len = (self.len - len_pos - 2) as u16;
+        write_u16(self.buffer, len_pos, data_len);
+        Some(())
+    }
+}
@@ -0,0 +1,151 @@
+//! Firmware images for OTA updates are signed with an Ed25519 key that is kept out of the
+//! repository. Generate the key pair once, and extract the raw public key:
+//!
+//! ```sh
+//! mkdir -p keys
+//! openssl genpkey -algorithm ed25519 -out keys/ota_signing_key.pem
+//! openssl pkey -in keys/ota_signing_key.pem -pubout -outform DER | tail -c 32 > keys/ota_public_key.bin
+//! ```
+//!
+//! The public key is embedded when building, the build fails without it:
+//!
+//! ```sh
+//! OTA_PUBLIC_KEY=$PWD/keys/ota_public_key.bin cargo build --release
+//! ```
+//!
+//! The signature covers the SHA-256 digest of the image and is appended to it:
+//!
+//! ```sh
+//! espflash save-image --chip esp32c3 target/riscv32imc-unknown-none-elf/release/lightbringer firmware.bin
+//! openssl dgst -sha256 -binary firmware.bin > firmware.sha256
+//! openssl pkeyutl -sign -rawin -inkey keys/ota_signing_key.pem -in firmware.sha256 -out firmware.sig
+//! cat firmware.bin firmware.sig > firmware.signed.bin
+//! ```
+
+use ed25519_compact::{PublicKey, Signature};
+use embedded_io_async::{ErrorKind, ErrorType, Read, ReadExactError};
+use sha2::{Digest, Sha256};
+
+pub const SIGNATURE_LEN: usize = Signature::BYTES;
+
+/// Raw public key, only images signed with the matching private key are installed
+const PUBLIC_KEY: &[u8; PublicKey::BYTES] = include_bytes!(env!(
+    "OTA_PUBLIC_KEY",
+    "Set OTA_PUBLIC_KEY to the absolute path of the raw OTA public key, see src/firmware_signature.rs"
+));
+
+#[derive(Debug)]
+pub enum SignedImageError<E> {
+    Read(E),
+    /// The body ended before the image and signature were complete
+    Truncated,
+    InvalidSignature,
+}
+
+impl<E: embedded_io_async::Error> embedded_io_async::Error for SignedImageError<E> {
+    fn kind(&self) -> ErrorKind {
+        match self {
+            SignedImageError::Read(error) => error.kind(),
+            SignedImageError::Truncated | SignedImageError::InvalidSignature => {
+                ErrorKind::InvalidData
+            }
+        }
+    }
+}
+
+/// Passes the image through while hashing it, and only reports the end of the image once
+/// the signature after it is valid. The new partition is only marked bootable after
+/// `is_verified`, so an image with an invalid signature is never booted.
+pub struct SignedImageReader<R> {
+    reader: R,
+    /// Image bytes that have not been read yet
+    remaining: usize,
+    hasher: Sha256,
+    verification: Verification,
+}
+
+#[derive(Copy, Clone, Eq, PartialEq)]
+enum Verification {
+    Pending,
+    Valid,
+    Rejected,
+}
+
+impl<R: Read> SignedImageReader<R> {
+    /// `len` is the length of the image including the signature
+    pub fn new(reader: R, len: usize) -> Self {
+        Self {
+            reader,
+            remaining: len.saturating_sub(SIGNATURE_LEN),
+            hasher: Sha256::new(),
+            verification: if len < SIGNATURE_LEN {
+                Verification::Rejected
+            } else {
+                Verification::Pending
+            },
+        }
+    }
+
+    /// Whether the image was refused because it is truncated or not signed with our key
+    pub fn is_rejected(&self) -> bool {
+        self.verification == Verification::Rejected
+    }
+
+    /// Whether the whole image was read and its signature is valid
+    pub fn is_verified(&self) -> bool {
+        self.verification == Verification::Valid
+    }
+
+    async fn verify(&mut self) -> Result<(), SignedImageError<R::Error>> {
+        let mut signature = [0; SIGNATURE_LEN];
+        self.reader
+            .read_exact(&mut signature)
+            .await
+            .map_err(|error| match error {
+                ReadExactError::UnexpectedEof => SignedImageError::Truncated,
+                ReadExactError::Other(error) => SignedImageError::Read(error),
+            })?;
+        let digest = core::mem::take(&mut self.hasher).finalize();
+        PublicKey::new(*PUBLIC_KEY)
+            .verify(digest, &Signature::new(signature))
+            .map_err(|_| SignedImageError::InvalidSignature)
+    }
+}
+
+impl<R: Read> ErrorType for SignedImageReader<R> {
+    type Error = SignedImageError<R::Error>;
+}
+
+impl<R: Read> Read for SignedImageReader<R> {
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        match self.verification {
+            Verification::Rejected => return Err(SignedImageError::InvalidSignature),
+            Verification::Valid => return Ok(0),
+            Verification::Pending if self.remaining == 0 => {
+                if let Err(error) = self.verify().await {
+                    log::warn!("Firmware signature check failed: {error:?}");
+                    self.verification = Verification::Rejected;
+                    return Err(error);
+                }
+                log::info!("Firmware signature is valid");
+                self.verification = Verification::Valid;
+                return Ok(0);
+            }
+            Verification::Pending => {}
+        }
+
+        let len = buf.len().min(self.remaining);
+        let read = self
+            .reader
+            .read(&mut buf[..len])
+            .await
+            .map_err(SignedImageError::Read)?;
+        if read == 0 && len != 0 {
+            self.verification = Verification::Rejected;
+            return Err(SignedImageError::Truncated);
+        }
+        self.hasher.update(&buf[..read]);
+        self.remaining -= read;
+        Ok(read)
+    }
+}
@@ -0,0 +1,368 @@
+use crate::journal::{store_record, Journal, RecordKind, SharedJournal};
+use crate::ota_data::{OtaData, OtaEntry, SlotState};
+use crate::ota_pull::store_rejected_version;
+use crate::wifi::WifiProvisioning;
+use crate::ESP_APP_DESC;
+use core::cell::Cell;
+use embassy_executor::Spawner;
+use embassy_futures::select::{select3, Either3};
+use embassy_net::Stack;
+use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::signal::Signal;
+use embassy_time::{with_deadline, Duration, Instant, Timer};
+use embedded_io_async::Read;
+use esp_hal::system::software_reset;
+use picoserve::request::RequestParts;
+use picoserve::response::ResponseWriter;
+use picoserve::routing::{Layer, Next};
+use picoserve::ResponseSent;
+use serde::{Deserialize, Serialize};
+
+const CONFIG_RECORD_VERSION: u8 = 1;
+const HEALTH_CHECK_CONFIG_LEN: usize = 5;
+const HISTORY_RECORD_VERSION: u8 = 1;
+pub const MAX_BOOT_HISTORY: usize = 8;
+const BOOT_HISTORY_LEN: usize = 1 + 2 * MAX_BOOT_HISTORY;
+const ATTEMPTS_RECORD_VERSION: u8 = 1;
+/// `[SEQ: 4, ATTEMPTS]`, where the sequence number of the otadata entry identifies the firmware
+const BOOT_ATTEMPTS_LEN: usize = 5;
+/// A new firmware that restarts more often before its health check ends is rolled back
+const MAX_BOOT_ATTEMPTS: u8 = 3;
+
+pub type SharedHealthCheckConfig = Mutex<NoopRawMutex, Cell<HealthCheckConfig>>;
+
+/// Signalled when the HTTP server answered a request
+pub type RequestServed = Signal<NoopRawMutex, ()>;
+
+/// What a new firmware has to do before it is accepted
+#[derive(Copy, Clone, Debug, Deserialize, Serialize)]
+pub struct HealthCheckConfig {
+    /// How long the network has to stay up without interruption
+    pub network_secs: u16,
+    /// Whether the web interface has to answer a request
+    pub require_request: bool,
+    /// Time since boot after which the firmware is rolled back
+    pub timeout_secs: u16,
+}
+
+impl Default for HealthCheckConfig {
+    fn default() -> Self {
+        Self {
+            network_secs: 60,
+            require_request: false,
+            timeout_secs: 300,
+        }
+    }
+}
+
+impl HealthCheckConfig {
+    /// Reads `[NETWORK_SECS: 2, REQUIRE_REQUEST, TIMEOUT_SECS: 2]`
+    pub fn from_bytes(bytes: &[u8; HEALTH_CHECK_CONFIG_LEN]) -> Self {
+        Self {
+            network_secs: u16::from_le_bytes([bytes[0], bytes[1]]),
+            require_request: bytes[2] != 0,
+            timeout_secs: u16::from_le_bytes([bytes[3], bytes[4]]),
+        }
+    }
+
+    pub fn into_bytes(self) -> [u8; HEALTH_CHECK_CONFIG_LEN] {
+        let [n0, n1] = self.network_secs.to_le_bytes();
+        let [t0, t1] = self.timeout_secs.to_le_bytes();
+        [n0, n1, self.require_request as u8, t0, t1]
+    }
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BootOutcome {
+    Accepted = 0,
+    /// Rolled back because no address was received
+    NoNetwork = 1,
+    /// Rolled back because the network kept dropping
+    NetworkUnstable = 2,
+    /// Rolled back because no HTTP request was served
+    NoRequest = 3,
+    /// Rolled back because it restarted before its health check ended
+    Crashed = 4,
+}
+
+impl BootOutcome {
+    fn from_raw(raw: u8) -> Option<Self> {
+        Some(match raw {
+            0 => BootOutcome::Accepted,
+            1 => BootOutcome::NoNetwork,
+            2 => BootOutcome::NetworkUnstable,
+            3 => BootOutcome::NoRequest,
+            4 => BootOutcome::Crashed,
+            _ => return None,
+        })
+    }
+}
+
+/// Result of the health check of a firmware update
+#[derive(Copy, Clone, Debug, Serialize)]
+pub struct BootRecord {
+    /// OTA slot the update was installed in
+    pub slot: u8,
+    pub outcome: BootOutcome,
+}
+
+/// Oldest record first
+pub type BootHistory = heapless::Vec<BootRecord, MAX_BOOT_HISTORY>;
+
+pub fn read_health_check_config(journal: &mut Journal) -> HealthCheckConfig {
+    let mut buffer = [0; HEALTH_CHECK_CONFIG_LEN];
+    match journal.read_latest(RecordKind::HealthCheckConfig, &mut buffer) {
+        None => HealthCheckConfig::default(),
+        Some((CONFIG_RECORD_VERSION, HEALTH_CHECK_CONFIG_LEN)) => {
+            HealthCheckConfig::from_bytes(&buffer)
+        }
+        Some((version, _)) => {
+            log::warn!("Unknown health check config record version {version}");
+            HealthCheckConfig::default()
+        }
+    }
+}
+
+pub fn store_health_check_config(journal: &SharedJournal, config: HealthCheckConfig) -> bool {
+    let stored = store_record(
+        journal,
+        RecordKind::HealthCheckConfig,
+        CONFIG_RECORD_VERSION,
+        &config.into_bytes(),
+    );
+    if stored {
+        log::info!("Health check config updated");
+    }
+    stored
+}
+
+/// Reads `[COUNT, (SLOT, OUTCOME)..]`
+pub fn read_boot_history(journal: &mut Journal) -> BootHistory {
+    let mut buffer = [0; BOOT_HISTORY_LEN];
+    match journal.read_latest(RecordKind::BootHistory, &mut buffer) {
+        Some((HISTORY_RECORD_VERSION, len)) if len > 0 => buffer[1..len]
+            .chunks_exact(2)
+            .take(buffer[0] as usize)
+            .filter_map(|record| {
+                Some(BootRecord {
+                    slot: record[0],
+                    outcome: BootOutcome::from_raw(record[1])?,
+                })
+            })
+            .collect(),
+        Some((version, _)) => {
+            log::warn!("Unknown boot history record version {version}");
+            BootHistory::new()
+        }
+        None => BootHistory::new(),
+    }
+}
+
+/// Adds a record to the history, dropping the oldest when it is full
+fn record_boot(journal: &SharedJournal, record: BootRecord) {
+    let result = journal.lock(|journal| {
+        let mut journal = journal.borrow_mut();
+        let mut history = read_boot_history(&mut journal);
+        if history.is_full() {
+            history.remove(0);
+        }
+        history.push(record).unwrap();
+
+        let mut bytes = heapless::Vec::<u8, BOOT_HISTORY_LEN>::new();
+        bytes.push(history.len() as u8).unwrap();
+        for record in &history {
+            bytes
+                .extend_from_slice(&[record.slot, record.outcome as u8])
+                .unwrap();
+        }
+        journal.append(RecordKind::BootHistory, HISTORY_RECORD_VERSION, &bytes)
+    });
+    if let Err(e) = result {
+        log::warn!("Storing boot history failed: {e:?}");
+    }
+    log::info!(
+        "Firmware in slot {} checked: {:?}",
+        record.slot,
+        record.outcome
+    );
+}
+
+pub struct HealthCheckContext {
+    pub config: &'static SharedHealthCheckConfig,
+    pub journal: &'static SharedJournal,
+    pub served: &'static RequestServed,
+    pub provisioning: &'static WifiProvisioning,
+}
+
+/// Counts the boots of a firmware that wasn't accepted yet, and rolls it back once it restarted
+/// too often, as a firmware that crashes early never finishes its health check. A bootloader with
+/// rollback support already aborts a `pending_verify` firmware on its next boot, other bootloaders
+/// keep booting it until the previous slot is activated.
+pub fn count_boot_attempt(journal: &SharedJournal) {
+    let entry = match OtaData::open().and_then(|mut ota_data| ota_data.active()) {
+        Ok(Some(entry)) if entry.state.is_pending() => entry,
+        _ => return,
+    };
+    let seq = entry.seq().to_le_bytes();
+    let attempts = journal.lock(|journal| {
+        let mut buffer = [0; BOOT_ATTEMPTS_LEN];
+        let record = journal
+            .borrow_mut()
+            .read_latest(RecordKind::BootAttempts, &mut buffer);
+        match record {
+            Some((ATTEMPTS_RECORD_VERSION, BOOT_ATTEMPTS_LEN)) if buffer[..4] == seq => buffer[4],
+            _ => 0,
+        }
+    });
+    let attempts = attempts.saturating_add(1);
+    if attempts > MAX_BOOT_ATTEMPTS {
+        record_boot(
+            journal,
+            BootRecord {
+                slot: entry.slot(),
+                outcome: BootOutcome::Crashed,
+            },
+        );
+        roll_back(&entry, journal);
+    }
+    let [s0, s1, s2, s3] = seq;
+    store_record(
+        journal,
+        RecordKind::BootAttempts,
+        ATTEMPTS_RECORD_VERSION,
+        &[s0, s1, s2, s3, attempts],
+    );
+    log::info!("Boot attempt {attempts} of new firmware");
+}
+
+/// Accepts a new firmware once it proved to work, and otherwise boots the previous one
+pub fn setup_health_check(stack: Stack<'static>, ctx: HealthCheckContext, spawner: Spawner) {
+    spawner.must_spawn(health_check_task(stack, ctx));
+}
+
+#[embassy_executor::task]
+async fn health_check_task(stack: Stack<'static>, ctx: HealthCheckContext) {
+    let active = OtaData::open().and_then(|mut ota_data| ota_data.active());
+    let entry = match active {
+        Ok(Some(entry)) if entry.state.is_pending() => entry,
+        // Flashed over USB or already checked, so there is nothing to roll back to
+        Ok(_) => return,
+        Err(e) => {
+            log::warn!("Reading otadata failed, keeping firmware: {e:?}");
+            return;
+        }
+    };
+
+    let config = ctx.config.lock(|config| config.get());
+    log::info!("Checking new firmware: {config:?}");
+    let deadline = Instant::now() + Duration::from_secs(config.timeout_secs.into());
+    let outcome = check(stack, &ctx, config, deadline).await;
+    record_boot(
+        ctx.journal,
+        BootRecord {
+            slot: entry.slot(),
+            outcome,
+        },
+    );
+    if outcome == BootOutcome::Accepted {
+        accept(&entry);
+        return;
+    }
+
+    log::warn!("New firmware failed its health check");
+    roll_back(&entry, ctx.journal);
+}
+
+/// Marks the running firmware invalid and restarts into the previous one. Its slot is activated
+/// with a newer entry, as some bootloaders only follow the sequence number and ignore the state.
+fn roll_back(entry: &OtaEntry, journal: &SharedJournal) -> ! {
+    log::warn!("Rolling back firmware in slot {}...", entry.slot());
+    store_rejected_version(journal, ESP_APP_DESC.version());
+    let result = OtaData::open().and_then(|mut ota_data| {
+        ota_data.set_state(entry, SlotState::Invalid)?;
+        ota_data.activate(1 - entry.slot(), SlotState::Valid)
+    });
+    if let Err(e) = result {
+        log::warn!("Rolling back firmware failed: {e:?}");
+    }
+    software_reset();
+}
+
+/// Marks the running firmware as valid, a failure is retried on the next boot
+fn accept(entry: &OtaEntry) {
+    if let Err(e) =
+        OtaData::open().and_then(|mut ota_data| ota_data.set_state(entry, SlotState::Valid))
+    {
+        log::warn!("Accepting firmware failed: {e:?}");
+    }
+}
+
+async fn check(
+    stack: Stack<'static>,
+    ctx: &HealthCheckContext,
+    config: HealthCheckConfig,
+    deadline: Instant,
+) -> BootOutcome {
+    // A lamp without networks, updated over the provisioning access point, can't connect
+    if ctx.provisioning.networks().is_empty() {
+        log::info!("No wifi networks saved, skipping the network check");
+    } else if let Err(outcome) = wait_for_stable_network(stack, config, deadline).await {
+        return outcome;
+    }
+    if config.require_request && with_deadline(deadline, ctx.served.wait()).await.is_err() {
+        return BootOutcome::NoRequest;
+    }
+    BootOutcome::Accepted
+}
+
+/// The network has to stay up for the whole period in one go
+async fn wait_for_stable_network(
+    stack: Stack<'static>,
+    config: HealthCheckConfig,
+    deadline: Instant,
+) -> Result<(), BootOutcome> {
+    loop {
+        if with_deadline(deadline, stack.wait_config_up())
+            .await
+            .is_err()
+        {
+            return Err(BootOutcome::NoNetwork);
+        }
+        let stable = Timer::after(Duration::from_secs(config.network_secs.into()));
+        let event = select3(stable, stack.wait_config_down(), Timer::at(deadline)).await;
+        match event {
+            Either3::First(()) => return Ok(()),
+            Either3::Second(()) => log::info!("Network lost during health check"),
+            Either3::Third(()) => return Err(BootOutcome::NetworkUnstable),
+        }
+    }
+}
+
+/// Signals `RequestServed` for every request that reaches the routes
+pub struct ServedLayer {
+    pub served: &'static RequestServed,
+}
+
+impl<State, PathParameters> Layer<State, PathParameters> for ServedLayer {
+    type NextState = State;
+    type NextPathParameters = PathParameters;
+
+    async fn call_layer<
+        'a,
+        R: Read + 'a,
+        NextLayer: Next<'a, R, Self::NextState, Self::NextPathParameters>,
+        W: ResponseWriter<Error = R::Error>,
+    >(
+        &self,
+        next: NextLayer,
+        state: &State,
+        path_parameters: PathParameters,
+        _request_parts: RequestParts<'_>,
+        response_writer: W,
+    ) -> Result<ResponseSent, W::Error> {
+        self.served.signal(());
+        next.run(state, path_parameters, response_writer).await
+    }
+}
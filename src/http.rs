@@ -1,18 +1,23 @@
-use crate::web_app::AppRouter;
+use crate::web_app::{AppRouter, PortalRouter};
 use embassy_executor::Spawner;
 use embassy_net::Stack;
 use embassy_time::Duration;
 use picoserve::make_static;
+use picoserve::routing::PathRouter;
 use picoserve::*;
 
 const PORT: u16 = 80;
 const MAX_CONNECTIONS: usize = 8;
-pub(crate) const MAX_LISTENERS: usize = MAX_CONNECTIONS + 4;
+/// Connections served on the provisioning access point
+const PORTAL_CONNECTIONS: usize = 2;
+pub(crate) const MAX_LISTENERS: usize = MAX_CONNECTIONS + PORTAL_CONNECTIONS + 4;
 
 pub async fn setup_http_server(
     stack: Stack<'static>,
+    ap_stack: Stack<'static>,
     spawner: Spawner,
     app: &'static Router<AppRouter>,
+    portal: &'static Router<PortalRouter>,
 ) {
     let config = make_static!(
         Config<Duration>,
@@ -26,6 +31,9 @@ pub async fn setup_http_server(
     for id in 0..MAX_CONNECTIONS {
         spawner.must_spawn(web_task(id, stack, app, config));
     }
+    for id in MAX_CONNECTIONS..MAX_CONNECTIONS + PORTAL_CONNECTIONS {
+        spawner.must_spawn(portal_task(id, ap_stack, portal, config));
+    }
 }
 
 #[embassy_executor::task(pool_size = MAX_CONNECTIONS)]
@@ -34,6 +42,25 @@ async fn web_task(
     stack: Stack<'static>,
     app: &'static Router<AppRouter>,
     config: &'static Config<Duration>,
+) -> ! {
+    serve(id, stack, app, config).await
+}
+
+#[embassy_executor::task(pool_size = PORTAL_CONNECTIONS)]
+async fn portal_task(
+    id: usize,
+    stack: Stack<'static>,
+    app: &'static Router<PortalRouter>,
+    config: &'static Config<Duration>,
+) -> ! {
+    serve(id, stack, app, config).await
+}
+
+async fn serve<P: PathRouter>(
+    id: usize,
+    stack: Stack<'static>,
+    app: &'static Router<P>,
+    config: &'static Config<Duration>,
 ) -> ! {
     let mut tcp_rx_buffer = [0; 1024];
     let mut tcp_tx_buffer = [0; 1024];
@@ -0,0 +1,134 @@
+use embassy_net::dns::DnsQueryType;
+use embassy_net::tcp::TcpSocket;
+use embassy_net::{IpAddress, Stack};
+use embassy_time::Duration;
+use embedded_io_async::{ErrorType, Read, Write};
+use heapless::String;
+use lightbringer_core::url::parse_url;
+
+pub const MAX_URL_LEN: usize = 128;
+const MAX_HEADER_LEN: usize = 1024;
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+pub type Url = String<MAX_URL_LEN>;
+
+#[derive(Debug)]
+pub enum HttpError {
+    InvalidUrl,
+    Dns,
+    Connect(embassy_net::tcp::ConnectError),
+    Io(embassy_net::tcp::Error),
+    /// The server answered with a status other than 200
+    Status(u16),
+    Protocol(&'static str),
+}
+
+impl From<embassy_net::tcp::Error> for HttpError {
+    fn from(value: embassy_net::tcp::Error) -> Self {
+        Self::Io(value)
+    }
+}
+
+/// Body of a response, which ends at the `Content-Length` or when the server closes the connection
+pub struct HttpResponse<'a> {
+    socket: TcpSocket<'a>,
+    pub content_length: Option<usize>,
+    remaining: usize,
+}
+
+impl ErrorType for HttpResponse<'_> {
+    type Error = embassy_net::tcp::Error;
+}
+
+impl Read for HttpResponse<'_> {
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        let len = buf.len().min(self.remaining);
+        if len == 0 {
+            return Ok(0);
+        }
+        let read = self.socket.read(&mut buf[..len]).await?;
+        self.remaining -= read;
+        Ok(read)
+    }
+}
+
+/// Sends a `GET` request. HTTP/1.0 is used so the body is never chunked.
+pub async fn get<'a>(
+    stack: Stack<'static>,
+    url: &str,
+    rx_buffer: &'a mut [u8],
+    tx_buffer: &'a mut [u8],
+) -> Result<HttpResponse<'a>, HttpError> {
+    let url = parse_url(url).ok_or(HttpError::InvalidUrl)?;
+    let address = match url.host.parse::<core::net::IpAddr>() {
+        Ok(address) => IpAddress::from(address),
+        Err(_) => *stack
+            .dns_query(url.host, DnsQueryType::A)
+            .await
+            .map_err(|_| HttpError::Dns)?
+            .first()
+            .ok_or(HttpError::Dns)?,
+    };
+
+    let mut socket = TcpSocket::new(stack, rx_buffer, tx_buffer);
+    socket.set_timeout(Some(TIMEOUT));
+    socket
+        .connect((address, url.port))
+        .await
+        .map_err(HttpError::Connect)?;
+    for part in [
+        "GET ",
+        url.path,
+        " HTTP/1.0\r\nHost: ",
+        url.host,
+        "\r\nUser-Agent: lightbringer\r\n\r\n",
+    ] {
+        socket.write_all(part.as_bytes()).await?;
+    }
+
+    // Read byte by byte, so no part of the body ends up in the header buffer
+    let mut header = [0; MAX_HEADER_LEN];
+    let mut len = 0;
+    while !header[..len].ends_with(b"\r\n\r\n") {
+        if len == MAX_HEADER_LEN {
+            return Err(HttpError::Protocol("Header too long"));
+        }
+        if socket.read(&mut header[len..len + 1]).await? == 0 {
+            return Err(HttpError::Protocol("Connection closed in header"));
+        }
+        len += 1;
+    }
+    let header = core::str::from_utf8(&header[..len])
+        .map_err(|_| HttpError::Protocol("Header is not UTF-8"))?;
+    let mut lines = header.split("\r\n");
+
+    let status = lines
+        .next()
+        .and_then(|line| line.strip_prefix("HTTP/1."))
+        .and_then(|line| line.get(2..5))
+        .and_then(|code| code.parse().ok())
+        .ok_or(HttpError::Protocol("Invalid status line"))?;
+    if status != 200 {
+        return Err(HttpError::Status(status));
+    }
+
+    let mut content_length = None;
+    for line in lines {
+        if let Some((name, value)) = line.split_once(':') {
+            if name.eq_ignore_ascii_case("content-length") {
+                content_length = Some(
+                    value
+                        .trim()
+                        .parse()
+                        .map_err(|_| HttpError::Protocol("Invalid Content-Length"))?,
+                );
+            }
+        }
+    }
+
+    Ok(HttpResponse {
+        socket,
+        content_length,
+        remaining: content_length.unwrap_or(usize::MAX),
+    })
+}
@@ -0,0 +1,355 @@
+use crate::partitions::{Partition, ReadWritePartitionError};
+use alloc::vec::Vec;
+use core::cell::RefCell;
+use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use lightbringer_core::journal::{
+    bank_layout, encode_bank_header, encode_entry, parse_bank_header, parse_header, EntryHeader,
+    BANK_HEADER_LEN, CRC, CRC_LEN, HEADER_LEN, MAX_PAYLOAD_LEN, WRITE_ALIGN,
+};
+use serde::Serialize;
+
+pub use lightbringer_core::journal::{read_str, write_str};
+
+const CHUNK_LEN: usize = 64;
+
+/// The type of data stored in an entry, only the newest valid entry of each kind is kept
+#[repr(u8)]
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum RecordKind {
+    LightState = 1,
+    DimmingCurve = 2,
+    WifiNetworks = 3,
+    DeviceName = 4,
+    NetworkConfig = 5,
+    MqttConfig = 6,
+    AuthConfig = 7,
+    ManifestUrl = 8,
+    HealthCheckConfig = 9,
+    BootHistory = 10,
+    BootAttempts = 11,
+    RejectedVersion = 12,
+    Presets = 13,
+}
+
+/// A journal that is written to from multiple tasks
+pub type SharedJournal = Mutex<NoopRawMutex, RefCell<Journal>>;
+
+/// Append-only log of records in a flash partition.
+/// New entries are written behind the previous ones, so a bank is only erased when full.
+/// Compaction copies the newest entries to the spare bank before the old bank is erased,
+/// so a power loss at any point keeps either the old or the new copy.
+pub struct Journal {
+    /// `None` when the partition could not be opened, then nothing is read or stored
+    partition: Option<Partition>,
+    /// 2, or 1 when the partition is a single sector and can't be compacted
+    banks: u32,
+    bank_size: u32,
+    region: Region,
+    /// Offset of the free space within the partition
+    head: u32,
+    status: StorageStatus,
+}
+
+/// Whether records can be stored, reported by `/api/storage`
+#[derive(Copy, Clone, Debug, Serialize)]
+pub struct StorageStatus {
+    pub available: bool,
+    pub failed_writes: u32,
+    /// Error of the last write, cleared when a write succeeds
+    pub last_error: Option<&'static str>,
+}
+
+/// Where the entries are stored
+#[derive(Copy, Clone, Debug)]
+enum Region {
+    /// Nothing was written yet, or the partition holds unrecognized data
+    Empty,
+    /// Entries behind the header of a bank
+    Bank { index: u32, seq: u32 },
+}
+
+impl Journal {
+    /// Falls back to a journal that stores nothing when the partition can't be opened,
+    /// so the lamp keeps working with the state it has in memory
+    pub fn open(partition_name: &str) -> Self {
+        let partition = Partition::open(partition_name)
+            .inspect_err(|e| {
+                log::error!(
+                    "Opening partition {partition_name} failed, changes won't be stored: {e:?}"
+                )
+            })
+            .ok();
+        let (banks, bank_size) = bank_layout(partition.as_ref().map_or(0, Partition::size));
+        if partition.is_some() && banks == 1 {
+            log::warn!("Partition {partition_name} has a single sector, the journal can't be compacted once it is full");
+        }
+        let mut journal = Self {
+            status: StorageStatus {
+                available: partition.is_some(),
+                failed_writes: 0,
+                last_error: partition.is_none().then_some("partition not found"),
+            },
+            partition,
+            banks,
+            bank_size,
+            region: Region::Empty,
+            head: 0,
+        };
+        journal.region = journal.find_region();
+        journal.head = journal.find_head();
+        let (start, end) = journal.bounds();
+        log::info!(
+            "Opened journal on {partition_name}, {} of {} bytes used",
+            journal.head - start,
+            end - start
+        );
+        journal
+    }
+
+    pub fn status(&self) -> StorageStatus {
+        self.status
+    }
+
+    fn partition(&mut self) -> Result<&mut Partition, ReadWritePartitionError> {
+        self.partition
+            .as_mut()
+            .ok_or(ReadWritePartitionError::PartitionNotFound)
+    }
+
+    fn read(&mut self, pos: u32, buffer: &mut [u8]) -> Result<(), ReadWritePartitionError> {
+        self.partition()?.read(pos, buffer)
+    }
+
+    /// The bank with the newest valid header
+    fn find_region(&mut self) -> Region {
+        let mut region = Region::Empty;
+        for index in 0..self.banks {
+            let mut raw = [0; BANK_HEADER_LEN as usize];
+            if self.read(index * self.bank_size, &mut raw).is_err() {
+                continue;
+            }
+            if let Some(seq) = parse_bank_header(&raw) {
+                match region {
+                    Region::Bank { seq: newest, .. } if newest >= seq => {}
+                    _ => region = Region::Bank { index, seq },
+                }
+            }
+        }
+        region
+    }
+
+    /// Range of the partition the entries of the current region are stored in
+    fn bounds(&self) -> (u32, u32) {
+        match self.region {
+            Region::Empty => (0, 0),
+            Region::Bank { index, .. } => {
+                let start = index * self.bank_size;
+                (start + BANK_HEADER_LEN, start + self.bank_size)
+            }
+        }
+    }
+
+    /// Walks the entries to find the start of the free space.
+    /// If unrecognized data is found the bank is considered full, so it is compacted on the next write.
+    fn find_head(&mut self) -> u32 {
+        let (mut pos, end) = self.bounds();
+        while pos + HEADER_LEN <= end {
+            let mut raw = [0; HEADER_LEN as usize];
+            if self.read(pos, &mut raw).is_err() {
+                return end;
+            }
+            if raw.iter().all(|b| *b == 0xFF) {
+                return pos;
+            }
+            let Some(header) = parse_header(&raw) else {
+                log::warn!("Unrecognized data in journal at {pos}");
+                return end;
+            };
+            pos += header.entry_len();
+        }
+        pos.min(end)
+    }
+
+    fn read_header(&mut self, pos: u32) -> Option<EntryHeader> {
+        let mut raw = [0; HEADER_LEN as usize];
+        self.read(pos, &mut raw).ok()?;
+        parse_header(&raw)
+    }
+
+    /// Checks the crc of the entry at `pos`, torn writes fail this check
+    fn is_valid(&mut self, pos: u32, header: EntryHeader) -> bool {
+        let data_len = HEADER_LEN + header.len;
+        let mut digest = CRC.digest();
+        let mut chunk = [0; CHUNK_LEN];
+        let mut read = 0;
+        while read < data_len {
+            let n = (data_len - read).min(CHUNK_LEN as u32);
+            // Reads must be aligned, so read whole words and only hash the requested bytes
+            let aligned = n.next_multiple_of(WRITE_ALIGN) as usize;
+            if self.read(pos + read, &mut chunk[..aligned]).is_err() {
+                return false;
+            }
+            digest.update(&chunk[..n as usize]);
+            read += n;
+        }
+
+        let mut stored = [0; CRC_LEN as usize];
+        let crc_pos = pos + header.entry_len() - CRC_LEN;
+        if self.read(crc_pos, &mut stored).is_err() {
+            return false;
+        }
+        digest.finalize() == u32::from_le_bytes(stored)
+    }
+
+    /// Position and header of the newest valid entry of each kind
+    fn latest_entries(&mut self) -> Vec<(u32, EntryHeader)> {
+        let mut latest: Vec<(u32, EntryHeader)> = Vec::new();
+        let (mut pos, _) = self.bounds();
+        while pos < self.head {
+            let Some(header) = self.read_header(pos) else {
+                break;
+            };
+            if self.is_valid(pos, header) {
+                latest.retain(|(_, h)| h.kind != header.kind);
+                latest.push((pos, header));
+            } else {
+                log::warn!("Skipping torn journal entry at {pos}");
+            }
+            pos += header.entry_len();
+        }
+        latest
+    }
+
+    /// Reads the newest valid record of `kind` into `buffer`, returning its version and length
+    pub fn read_latest(&mut self, kind: RecordKind, buffer: &mut [u8]) -> Option<(u8, usize)> {
+        let (pos, header) = self
+            .latest_entries()
+            .into_iter()
+            .find(|(_, h)| h.kind == kind as u8)?;
+
+        let len = header.len as usize;
+        if len > buffer.len() {
+            log::warn!("Journal record {kind:?} does not fit in buffer");
+            return None;
+        }
+        let mut payload = [0; MAX_PAYLOAD_LEN];
+        let aligned = len.next_multiple_of(WRITE_ALIGN as usize);
+        self.read(pos + HEADER_LEN, &mut payload[..aligned]).ok()?;
+        buffer[..len].copy_from_slice(&payload[..len]);
+        Some((header.version, len))
+    }
+
+    /// Appends a record, compacting the journal first if there is no space left
+    pub fn append(
+        &mut self,
+        kind: RecordKind,
+        version: u8,
+        payload: &[u8],
+    ) -> Result<(), ReadWritePartitionError> {
+        let result = self.try_append(kind, version, payload);
+        match &result {
+            Ok(()) => self.status.last_error = None,
+            Err(e) => {
+                self.status.failed_writes += 1;
+                self.status.last_error = Some(e.description());
+            }
+        }
+        result
+    }
+
+    fn try_append(
+        &mut self,
+        kind: RecordKind,
+        version: u8,
+        payload: &[u8],
+    ) -> Result<(), ReadWritePartitionError> {
+        if payload.len() > MAX_PAYLOAD_LEN {
+            log::warn!(
+                "Journal record {kind:?} of {} bytes is too large",
+                payload.len()
+            );
+            return Err(ReadWritePartitionError::OutOfBounds);
+        }
+        let entry = encode_entry(kind as u8, version, payload);
+
+        // The first write starts a bank
+        let has_bank = matches!(self.region, Region::Bank { .. });
+        if !has_bank || self.head + entry.len() as u32 > self.bounds().1 {
+            self.compact()?;
+            if self.head + entry.len() as u32 > self.bounds().1 {
+                log::warn!("Journal is full, dropping {kind:?} record");
+                return Err(ReadWritePartitionError::OutOfBounds);
+            }
+        }
+
+        let head = self.head;
+        self.partition()?.write(head, &entry)?;
+        self.head += entry.len() as u32;
+        Ok(())
+    }
+
+    /// Copies the newest valid entry of each kind to the spare bank, then erases the old bank
+    fn compact(&mut self) -> Result<(), ReadWritePartitionError> {
+        let (target, seq, old) = match self.region {
+            Region::Empty => (0, 1, None),
+            Region::Bank { index, seq } => {
+                ((index + 1) % self.banks, seq.wrapping_add(1), Some(index))
+            }
+        };
+        // Erasing the only bank loses every record if the power fails before they are rewritten
+        if old == Some(target) {
+            log::warn!("Journal has no spare bank to compact into");
+            return Err(ReadWritePartitionError::OutOfBounds);
+        }
+
+        log::info!("Compacting journal...");
+        let mut live = Vec::new();
+        for (pos, header) in self.latest_entries() {
+            let mut entry = alloc::vec![0; header.entry_len() as usize];
+            self.read(pos, &mut entry)?;
+            live.push(entry);
+        }
+
+        let start = target * self.bank_size;
+        let end = start + self.bank_size;
+        let partition = self.partition()?;
+        partition.erase(start, end)?;
+        let mut head = start + BANK_HEADER_LEN;
+        for entry in live {
+            if head + entry.len() as u32 > end {
+                return Err(ReadWritePartitionError::OutOfBounds);
+            }
+            partition.write(head, &entry)?;
+            head += entry.len() as u32;
+        }
+        // Writing the header last makes the new bank valid only once it is complete
+        partition.write(start, &encode_bank_header(seq))?;
+        self.region = Region::Bank { index: target, seq };
+        self.head = head;
+
+        if let Some(old) = old {
+            let old_start = old * self.bank_size;
+            let old_end = old_start + self.bank_size;
+            // A leftover old bank has a lower sequence number, so failing here is harmless
+            if let Err(e) = self.partition()?.erase(old_start, old_end) {
+                log::warn!("Erasing old journal bank failed: {e:?}");
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Appends a record, logging a failure instead of returning it since the value stays in memory
+pub fn store_record(
+    journal: &SharedJournal,
+    kind: RecordKind,
+    version: u8,
+    payload: &[u8],
+) -> bool {
+    let result = journal.lock(|journal| journal.borrow_mut().append(kind, version, payload));
+    if let Err(e) = &result {
+        log::warn!("Storing {kind:?} record failed: {e:?}");
+    }
+    result.is_ok()
+}
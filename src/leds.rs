@@ -1,10 +1,15 @@
+use crate::dimming_curve::SharedDimmingCurve;
 use crate::http::MAX_LISTENERS;
+use crate::led_output::{FadeDriver, HardwareFade, LedOutput};
 use crate::light_state::LightState;
 use crate::make_static;
+use crate::transition::{Fade, NextTransition};
 use crate::value_synchronizer::ValueSynchronizer;
+use core::future::pending;
 use embassy_executor::Spawner;
+use embassy_futures::select::{select3, Either3};
 use embassy_sync::blocking_mutex::raw::NoopRawMutex;
-use embassy_time::Timer as EmbassyTimer;
+use embassy_time::{Duration, Instant, Timer as EmbassyTimer};
 use esp_hal::gpio::interconnect::PeripheralOutput;
 use esp_hal::ledc::channel::config::PinConfig;
 use esp_hal::ledc::channel::{Channel, ChannelHW, ChannelIFace};
@@ -18,15 +23,18 @@ pub const DUTY: Duty = Duty12Bit;
 
 const STARTUP_DELAY: u64 = 0;
 const FADE_IN_TIME: u64 = 2000;
-const STEPS: u64 = 100;
+/// Frequency of the PWM signal, the timer runs from the 80 MHz APB clock
+const PWM_FREQUENCY: u64 = 80_000_000 >> (DUTY as u32);
 
 pub fn setup_leds(
     value: &'static ValueSynchronizer<MAX_LISTENERS, NoopRawMutex, LightState>,
+    curve: &'static SharedDimmingCurve,
     red: impl PeripheralOutput<'static>,
     blue: impl PeripheralOutput<'static>,
     ledc: esp_hal::peripherals::LEDC<'static>,
     spawner: Spawner,
-) {
+) -> &'static NextTransition {
+    let transition = make_static!(NextTransition, NextTransition::new());
     let ledc = make_static!(Ledc, Ledc::new(ledc));
     ledc.set_global_slow_clock(LSGlobalClkSource::APBClk);
 
@@ -38,7 +46,7 @@ pub fn setup_leds(
         .configure(timer::config::Config {
             duty: DUTY,
             clock_source: timer::LSClockSource::APBClk,
-            frequency: Rate::from_hz(80_000_000 >> (DUTY as u32)),
+            frequency: Rate::from_hz(PWM_FREQUENCY as u32),
         })
         .unwrap();
 
@@ -60,42 +68,87 @@ pub fn setup_leds(
         })
         .unwrap();
 
-    spawner.must_spawn(led_task(value, red_channel, blue_channel));
+    spawner.must_spawn(led_task(
+        value,
+        curve,
+        transition,
+        red_channel,
+        blue_channel,
+    ));
+    transition
+}
+
+impl LedOutput for Channel<'static, LowSpeed> {
+    fn set_duty(&mut self, duty: u32) {
+        self.set_duty_hw(duty);
+    }
+
+    fn start_fade(&mut self, from: u32, to: u32, duration: Duration) -> bool {
+        let pwm_cycles = duration.as_micros() * PWM_FREQUENCY / 1_000_000;
+        match HardwareFade::plan(from, to, pwm_cycles as u32) {
+            Some(fade) => self.start_duty_fade_hw(
+                fade.start,
+                fade.increase,
+                fade.steps,
+                fade.cycles_per_step,
+                fade.duty_per_step,
+            ),
+            None => self.set_duty_hw(to),
+        }
+        true
+    }
 }
 
 #[embassy_executor::task]
 async fn led_task(
     value: &'static ValueSynchronizer<MAX_LISTENERS, NoopRawMutex, LightState>,
+    curve: &'static SharedDimmingCurve,
+    transition: &'static NextTransition,
     red_channel: Channel<'static, LowSpeed>,
     blue_channel: Channel<'static, LowSpeed>,
 ) -> ! {
     let mut watcher = value.watch();
-
-    // Initial update
-    let message = value.read_clone();
-    let red = (message.warm as u32) << (DUTY as u32) >> 16;
-    let blue = (message.cold as u32) << (DUTY as u32) >> 16;
+    let mut curve_watcher = curve.watch();
+    let mut current_curve = curve.read_clone();
+    let mut driver = FadeDriver::new(red_channel, blue_channel, DUTY as u32);
 
     // Wait with starting
     EmbassyTimer::after_millis(STARTUP_DELAY).await;
     log::info!("Fading in leds...");
-    for i in 1..=STEPS {
-        EmbassyTimer::after_millis(FADE_IN_TIME / STEPS).await;
-        red_channel.set_duty_hw(red * i as u32 / STEPS as u32);
-        blue_channel.set_duty_hw(blue * i as u32 / STEPS as u32);
-    }
 
-    log::info!("Initial color set to {red} {blue}");
+    // Initial update fades in from off
+    let target = value.read_clone();
+    let mut fade = Fade::new(
+        LightState::new(0, target.temperature),
+        target,
+        Instant::now(),
+        Duration::from_millis(FADE_IN_TIME),
+    );
+    let mut next_step = driver.step(&fade, &current_curve, Instant::now());
 
     loop {
-        let message = watcher.read().await;
-
-        let red = (message.warm as u32) << (DUTY as u32) >> 16;
-        let blue = (message.cold as u32) << (DUTY as u32) >> 16;
-
-        red_channel.set_duty_hw(red);
-        blue_channel.set_duty_hw(blue);
+        let tick = async {
+            match next_step {
+                Some(at) => EmbassyTimer::at(at).await,
+                None => pending().await,
+            }
+        };
+        let event = select3(watcher.read(), curve_watcher.read(), tick).await;
+        let now = Instant::now();
+        match event {
+            // Retarget from wherever the current transition is
+            Either3::First(target) => {
+                fade = Fade::new(fade.at(now), target, now, transition.take());
+            }
+            Either3::Second(new_curve) => current_curve = new_curve,
+            Either3::Third(()) => {}
+        }
 
-        log::info!("Color set to {red} {blue}");
+        let was_fading = next_step.is_some();
+        next_step = driver.step(&fade, &current_curve, now);
+        if was_fading && next_step.is_none() {
+            let (red, blue) = driver.duties(fade.target(), &current_curve);
+            log::info!("Color set to {red} {blue}");
+        }
     }
 }
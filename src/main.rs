@@ -3,37 +3,85 @@
 #![feature(type_alias_impl_trait)]
 #![feature(impl_trait_in_assoc_type)]
 
+extern crate alloc;
+
+mod api;
+mod auth;
+mod captive_portal;
 mod color_storage;
+mod device_name;
+mod dhcp_server;
+mod dimming_curve;
+mod dns;
+mod firmware_signature;
+mod health_check;
 mod http;
+mod http_client;
+mod journal;
 mod leds;
-mod light_state;
+mod mdns;
+mod mqtt;
+mod mqtt_config;
+mod network_config;
+mod ota;
+mod ota_data;
+mod ota_pull;
+mod partitions;
+mod presets;
 mod rotating_logger;
+mod slaac;
 mod value_synchronizer;
 mod web_app;
 mod wifi;
+mod wifi_credentials;
 //mod app_desc;
 
+use crate::auth::{read_auth_config, setup_auth_reset, SharedAuthConfig};
+use crate::captive_portal::setup_captive_portal;
 use crate::color_storage::{read_light_state, setup_color_storage};
+use crate::device_name::{default_device_name, read_device_name, SharedDeviceName};
+use crate::dimming_curve::{read_dimming_curve, SharedDimmingCurve};
+use crate::health_check::{
+    count_boot_attempt, read_health_check_config, setup_health_check, HealthCheckContext,
+    RequestServed, SharedHealthCheckConfig,
+};
 use crate::http::setup_http_server;
 use crate::http::MAX_LISTENERS;
+use crate::journal::{Journal, SharedJournal};
 use crate::leds::setup_leds;
 use crate::light_state::LightState;
+use crate::mdns::{setup_mdns, MdnsInfo};
+use crate::mqtt::{setup_mqtt, MqttContext};
+use crate::mqtt_config::{read_mqtt_config, SharedMqttConfig};
+use crate::network_config::{read_ipv4_settings, SharedIpv4Settings};
+use crate::ota::SharedOtaProgress;
+use crate::ota_pull::{
+    read_manifest_url, setup_ota_pull, OtaPullContext, PullRequest, SharedManifestUrl,
+};
+use crate::presets::{read_presets, SharedPresets};
 use crate::rotating_logger::RingBufferLogger;
 use crate::value_synchronizer::ValueSynchronizer;
-use crate::web_app::{make_app, AppRouter};
-use crate::wifi::setup_wifi;
+use crate::web_app::{make_app, make_portal_app, AppContext, AppRouter, PortalRouter};
+use crate::wifi::{setup_wifi, wait_for_ip};
+use crate::wifi_credentials::read_wifi_networks;
 use build_time::build_time_local;
+use core::cell::{Cell, RefCell};
 use embassy_executor::Spawner;
 use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::signal::Signal;
 use esp_backtrace as _;
 use esp_hal::clock::CpuClock;
+use esp_hal::efuse::Efuse;
 use esp_hal::gpio::Level::{High, Low};
 use esp_hal::gpio::{Output, OutputConfig};
+use esp_hal::rng::Rng;
 use esp_hal::timer::timg::TimerGroup;
 use esp_hal::Config;
 use esp_hal_embassy::main;
-use esp_ota_nostd::{get_booted_partition, ota_accept};
+use esp_ota_nostd::get_booted_partition;
 use esp_storage::FlashStorage;
+use lightbringer_core::{led_output, light_state, transition};
 use picoserve::{make_static, Router};
 
 esp_bootloader_esp_idf::esp_app_desc!();
@@ -45,10 +93,15 @@ async fn main(spawner: Spawner) {
 
     // Hardware init
     let mut storage = FlashStorage::new();
-    let partition = get_booted_partition(&mut storage).unwrap();
+    let partition = get_booted_partition(&mut storage)
+        .inspect_err(|e| log::error!("Finding the booted partition failed: {e:?}"))
+        .ok();
+    let partition_name = partition
+        .as_ref()
+        .map_or("unknown", |partition| partition.name());
     log::info!(
         "Starting initialization from partition {} with build time {}...",
-        partition.name(),
+        partition_name,
         build_time_local!("%Y-%m-%dT%H:%M:%S%.f%:z")
     );
     let peripherals = esp_hal::init(Config::default().with_cpu_clock(CpuClock::max()));
@@ -66,27 +119,119 @@ async fn main(spawner: Spawner) {
     esp_hal_embassy::init(timg0.timer0);
 
     // Setup app
-    let initial_color = read_light_state();
+    // userdata is a single sector, nvs has room for the two banks the journal compacts between
+    let mut journal = Journal::open("nvs");
+    let initial_curve = read_dimming_curve(&mut journal);
+    let curve = make_static!(SharedDimmingCurve, ValueSynchronizer::new(initial_curve));
+    let initial_color = read_light_state(&mut journal, &initial_curve);
+    let presets = read_presets(&mut journal);
+    let presets = make_static!(SharedPresets, Mutex::new(RefCell::new(presets)));
     let value = make_static!(ValueSynchronizer<MAX_LISTENERS, NoopRawMutex, LightState>, ValueSynchronizer::new(initial_color));
-    setup_color_storage(spawner, value);
+    let journal = make_static!(SharedJournal, Mutex::new(RefCell::new(journal)));
+    count_boot_attempt(journal);
+    let save = setup_color_storage(spawner, value, journal);
 
     // Setup leds
-    setup_leds(value, red, blue, peripherals.LEDC, spawner);
+    let transition = setup_leds(value, curve, red, blue, peripherals.LEDC, spawner);
 
-    // Setup http
-    let app = make_static!(Router<AppRouter>, make_app(value, logger));
-    let stack = setup_wifi(
+    // Setup wifi
+    let networks = journal.lock(|journal| read_wifi_networks(&mut journal.borrow_mut()));
+    let name = journal
+        .lock(|journal| read_device_name(&mut journal.borrow_mut()))
+        .unwrap_or_else(|| default_device_name(Efuse::read_base_mac_address()));
+    let name = make_static!(SharedDeviceName, ValueSynchronizer::new(name));
+    let ipv4 = journal.lock(|journal| read_ipv4_settings(&mut journal.borrow_mut()));
+    let ipv4 = make_static!(SharedIpv4Settings, ValueSynchronizer::new(ipv4));
+    let mqtt = journal.lock(|journal| read_mqtt_config(&mut journal.borrow_mut()));
+    let mqtt = make_static!(SharedMqttConfig, ValueSynchronizer::new(mqtt));
+    let auth = journal.lock(|journal| read_auth_config(&mut journal.borrow_mut()));
+    let auth = make_static!(SharedAuthConfig, Mutex::new(RefCell::new(auth)));
+    setup_auth_reset(peripherals.GPIO9, auth, journal, spawner);
+    let rng = Rng::new(peripherals.RNG);
+    let ota = make_static!(SharedOtaProgress, Mutex::new(Cell::new(Default::default())));
+    let manifest = journal.lock(|journal| read_manifest_url(&mut journal.borrow_mut()));
+    let manifest = make_static!(SharedManifestUrl, ValueSynchronizer::new(manifest));
+    let pull = make_static!(PullRequest, Signal::new());
+    let health = journal.lock(|journal| read_health_check_config(&mut journal.borrow_mut()));
+    let health = make_static!(SharedHealthCheckConfig, Mutex::new(Cell::new(health)));
+    let served = make_static!(RequestServed, Signal::new());
+    let wifi = setup_wifi(
         peripherals.SYSTIMER,
-        peripherals.RNG,
+        rng,
         peripherals.WIFI,
         spawner,
-    )
-    .await;
-
-    setup_http_server(stack, spawner, app).await;
+        networks,
+        name,
+        ipv4,
+    );
+    setup_captive_portal(wifi.ap, spawner);
 
-    // Accept ota
-    ota_accept(&mut storage).unwrap();
+    // Setup http
+    let ctx = AppContext {
+        data: value,
+        curve,
+        transition,
+        save,
+        journal,
+        presets,
+        provisioning: wifi.provisioning,
+        name,
+        ipv4,
+        mqtt,
+        auth,
+        rng,
+        stack: wifi.sta,
+        logger,
+        ota,
+        manifest,
+        pull,
+        health,
+        served,
+    };
+    let app = make_static!(Router<AppRouter>, make_app(ctx));
+    let portal = make_static!(Router<PortalRouter>, make_portal_app(ctx));
+    setup_http_server(wifi.sta, wifi.ap, spawner, app, portal).await;
+    setup_mdns(
+        wifi.sta,
+        MdnsInfo {
+            name,
+            // Partition names have at most 16 bytes, anything else is announced as empty
+            partition: partition_name.try_into().unwrap_or_default(),
+        },
+        spawner,
+    );
+    setup_mqtt(
+        wifi.sta,
+        MqttContext {
+            data: value,
+            transition,
+            save,
+            name,
+            config: mqtt,
+        },
+        spawner,
+    );
+    setup_ota_pull(
+        wifi.sta,
+        OtaPullContext {
+            progress: ota,
+            journal,
+            manifest,
+            request: pull,
+        },
+        spawner,
+    );
+    setup_health_check(
+        wifi.sta,
+        HealthCheckContext {
+            config: health,
+            journal,
+            served,
+            provisioning: wifi.provisioning,
+        },
+        spawner,
+    );
+    wait_for_ip(wifi.sta).await;
     setup_pin.set_low();
 
     log::info!("Running...")
@@ -0,0 +1,325 @@
+use crate::device_name::SharedDeviceName;
+use crate::dns::{
+    read_name, read_u16, skip_name, write_u16, Writer, CLASS_IN, FLAG_AUTHORITATIVE, FLAG_RESPONSE,
+    HEADER_LEN, TYPE_A, TYPE_AAAA, TYPE_ANY, TYPE_PTR, TYPE_SRV, TYPE_TXT,
+};
+use crate::ESP_APP_DESC;
+use embassy_executor::Spawner;
+use embassy_futures::select::{select3, Either3};
+use embassy_net::udp::{PacketMetadata, UdpSocket};
+use embassy_net::{IpEndpoint, Ipv4Address, Ipv6Address, Stack};
+use embassy_time::Timer;
+use heapless::String;
+
+const MDNS_PORT: u16 = 5353;
+const MDNS_ADDRESS: Ipv4Address = Ipv4Address::new(224, 0, 0, 251);
+const MAX_PACKET_LEN: usize = 512;
+const HTTP_PORT: u16 = 80;
+
+const HOST_TTL: u32 = 120;
+const SERVICE_TTL: u32 = 4500;
+/// Resolvers that don't speak mDNS cache responses no longer than this
+const LEGACY_TTL: u32 = 10;
+/// Marks a record as the only one with its name, so caches replace older versions
+const CACHE_FLUSH: u16 = 0x8000;
+/// Set in the class of a question that asks for a unicast response
+const UNICAST_RESPONSE: u16 = 0x8000;
+
+/// The services that are announced for the web interface
+const SERVICES: [&str; 2] = ["_http._tcp", "_lightbringer._tcp"];
+const SERVICE_ENUMERATION: &str = "_services._dns-sd._udp";
+const LOCAL: &str = "local";
+
+/// What the lamp announces about itself
+pub struct MdnsInfo {
+    pub name: &'static SharedDeviceName,
+    pub partition: String<16>,
+}
+
+/// Starts answering mDNS queries for `<hostname>.local` and the lamp's services
+pub fn setup_mdns(stack: Stack<'static>, info: MdnsInfo, spawner: Spawner) {
+    if let Err(e) = stack.join_multicast_group(MDNS_ADDRESS) {
+        log::warn!("Failed to join mDNS multicast group: {e:?}");
+        return;
+    }
+    spawner.must_spawn(mdns_task(stack, info));
+}
+
+#[embassy_executor::task]
+async fn mdns_task(stack: Stack<'static>, info: MdnsInfo) -> ! {
+    let mut rx_meta = [PacketMetadata::EMPTY; 4];
+    let mut rx_buffer = [0; MAX_PACKET_LEN];
+    let mut tx_meta = [PacketMetadata::EMPTY; 4];
+    let mut tx_buffer = [0; MAX_PACKET_LEN];
+    let mut socket = UdpSocket::new(
+        stack,
+        &mut rx_meta,
+        &mut rx_buffer,
+        &mut tx_meta,
+        &mut tx_buffer,
+    );
+    socket.bind(MDNS_PORT).unwrap();
+
+    let multicast = IpEndpoint::new(MDNS_ADDRESS.into(), MDNS_PORT);
+    let mut packet = [0; MAX_PACKET_LEN];
+    let mut response = [0; MAX_PACKET_LEN];
+    let mut announced = None;
+    let mut watcher = info.name.watch();
+    loop {
+        let hostname = info.name.read_clone();
+        let address = stack.config_v4().map(|config| config.address.address());
+        if address != announced {
+            announced = address;
+            if let Some(address) = address {
+                log::info!("Announcing {hostname}.local at {address}");
+                // Announcements are sent twice, in case the first one is lost
+                for _ in 0..2 {
+                    let answers = Answers::all();
+                    let address6 = stack.config_v6().map(|config| config.address.address());
+                    if let Some(len) = write_response(
+                        &mut response,
+                        answers,
+                        &hostname,
+                        &info,
+                        (address, address6),
+                        None,
+                    ) {
+                        socket.send_to(&response[..len], multicast).await.ok();
+                    }
+                    Timer::after_secs(1).await;
+                }
+            }
+        }
+
+        // Wake up regularly to notice a changed address
+        let event = select3(
+            socket.recv_from(&mut packet),
+            watcher.read(),
+            Timer::after_secs(5),
+        )
+        .await;
+        let (len, meta) = match event {
+            Either3::First(Ok(received)) => received,
+            // Announce the new name
+            Either3::Second(_) => {
+                announced = None;
+                continue;
+            }
+            _ => continue,
+        };
+        let Some(address) = address else {
+            continue;
+        };
+        let Some((answers, unicast, questions)) = Answers::for_query(&packet[..len], &hostname)
+        else {
+            continue;
+        };
+
+        // Simple resolvers that don't use the mDNS port expect a normal DNS response
+        let legacy = meta.endpoint.port != MDNS_PORT;
+        let query = legacy.then_some(&packet[..questions]);
+        let address6 = stack.config_v6().map(|config| config.address.address());
+        let Some(len) = write_response(
+            &mut response,
+            answers,
+            &hostname,
+            &info,
+            (address, address6),
+            query,
+        ) else {
+            continue;
+        };
+        let destination = match legacy || unicast {
+            true => meta.endpoint,
+            false => multicast,
+        };
+        socket.send_to(&response[..len], destination).await.ok();
+    }
+}
+
+/// The records that are sent in a response
+#[derive(Copy, Clone, Default)]
+struct Answers {
+    address: bool,
+    address6: bool,
+    enumeration: bool,
+    pointers: [bool; SERVICES.len()],
+    instances: [bool; SERVICES.len()],
+}
+
+impl Answers {
+    fn all() -> Self {
+        Self {
+            address: true,
+            address6: true,
+            enumeration: true,
+            pointers: [true; SERVICES.len()],
+            instances: [true; SERVICES.len()],
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        !self.address
+            && !self.address6
+            && !self.enumeration
+            && !self.pointers.contains(&true)
+            && !self.instances.contains(&true)
+    }
+
+    /// The answers to the questions in a query, whether a unicast response was requested and
+    /// where the questions end
+    fn for_query(packet: &[u8], hostname: &str) -> Option<(Self, bool, usize)> {
+        if read_u16(packet, 2)? & FLAG_RESPONSE != 0 {
+            return None;
+        }
+        let mut answers = Self::default();
+        let mut unicast = false;
+        let mut pos = HEADER_LEN;
+        for _ in 0..read_u16(packet, 4)? {
+            let name: String<128> = read_name(packet, pos)?;
+            pos = skip_name(packet, pos)?;
+            let qtype = read_u16(packet, pos)?;
+            unicast |= read_u16(packet, pos + 2)? & UNICAST_RESPONSE != 0;
+            pos += 4;
+
+            let wants = |types: &[u16]| qtype == TYPE_ANY || types.contains(&qtype);
+            if is_name(&name, &[hostname, LOCAL]) && wants(&[TYPE_A]) {
+                answers.address = true;
+            }
+            if is_name(&name, &[hostname, LOCAL]) && wants(&[TYPE_AAAA]) {
+                answers.address6 = true;
+            }
+            if is_name(&name, &[SERVICE_ENUMERATION, LOCAL]) && wants(&[TYPE_PTR]) {
+                answers.enumeration = true;
+            }
+            for (i, service) in SERVICES.into_iter().enumerate() {
+                if is_name(&name, &[service, LOCAL]) && wants(&[TYPE_PTR]) {
+                    answers.pointers[i] = true;
+                    answers.instances[i] = true;
+                    answers.address = true;
+                    answers.address6 = true;
+                }
+                if is_name(&name, &[hostname, service, LOCAL]) && wants(&[TYPE_SRV, TYPE_TXT]) {
+                    answers.instances[i] = true;
+                    answers.address = true;
+                    answers.address6 = true;
+                }
+            }
+        }
+        (!answers.is_empty()).then_some((answers, unicast, pos))
+    }
+}
+
+/// Whether `name` consists of the dot separated `parts`, ignoring case
+fn is_name(name: &str, parts: &[&str]) -> bool {
+    let mut rest = name;
+    for (i, part) in parts.iter().enumerate() {
+        if i > 0 {
+            let Some(r) = rest.strip_prefix('.') else {
+                return false;
+            };
+            rest = r;
+        }
+        match rest.get(..part.len()) {
+            Some(label) if label.eq_ignore_ascii_case(part) => rest = &rest[part.len()..],
+            _ => return false,
+        }
+    }
+    rest.is_empty()
+}
+
+/// Writes a response with the requested answers, returning its length. A legacy `query`,
+/// its header and questions, is answered like a normal DNS server would.
+fn write_response(
+    buffer: &mut [u8],
+    answers: Answers,
+    hostname: &str,
+    info: &MdnsInfo,
+    (address, address6): (Ipv4Address, Option<Ipv6Address>),
+    query: Option<&[u8]>,
+) -> Option<usize> {
+    let mut w = Writer::new(buffer);
+    w.bytes(&[0; HEADER_LEN])?;
+    if let Some(query) = query {
+        // Compressed names in the questions stay valid, as they keep their offsets
+        w.bytes(&query[HEADER_LEN..])?;
+    }
+    let legacy = query.is_some();
+    let flush = if legacy { 0 } else { CACHE_FLUSH };
+    let ttl = |ttl: u32| if legacy { ttl.min(LEGACY_TTL) } else { ttl };
+    let mut count = 0;
+
+    if answers.address {
+        w.record(
+            &[hostname, LOCAL],
+            TYPE_A,
+            CLASS_IN | flush,
+            ttl(HOST_TTL),
+            |w| w.bytes(&address.octets()),
+        )?;
+        count += 1;
+    }
+    if let (true, Some(address6)) = (answers.address6, address6) {
+        w.record(
+            &[hostname, LOCAL],
+            TYPE_AAAA,
+            CLASS_IN | flush,
+            ttl(HOST_TTL),
+            |w| w.bytes(&address6.octets()),
+        )?;
+        count += 1;
+    }
+    for (i, service) in SERVICES.into_iter().enumerate() {
+        if answers.enumeration {
+            w.record(
+                &[SERVICE_ENUMERATION, LOCAL],
+                TYPE_PTR,
+                CLASS_IN,
+                ttl(SERVICE_TTL),
+                |w| w.name(&[service, LOCAL]),
+            )?;
+            count += 1;
+        }
+        if answers.pointers[i] {
+            w.record(
+                &[service, LOCAL],
+                TYPE_PTR,
+                CLASS_IN,
+                ttl(SERVICE_TTL),
+                |w| w.name(&[hostname, service, LOCAL]),
+            )?;
+            count += 1;
+        }
+        if answers.instances[i] {
+            let instance = [hostname, service, LOCAL];
+            w.record(&instance, TYPE_SRV, CLASS_IN | flush, ttl(HOST_TTL), |w| {
+                // Priority and weight
+                w.u16(0)?;
+                w.u16(0)?;
+                w.u16(HTTP_PORT)?;
+                w.name(&[hostname, LOCAL])
+            })?;
+            w.record(&instance, TYPE_TXT, CLASS_IN | flush, ttl(HOST_TTL), |w| {
+                txt_entry(w, "version", ESP_APP_DESC.version())?;
+                txt_entry(w, "partition", &info.partition)
+            })?;
+            count += 2;
+        }
+    }
+
+    let len = w.position();
+    if let Some(query) = query {
+        buffer[..2].copy_from_slice(&query[..2]);
+        buffer[4..6].copy_from_slice(&query[4..6]);
+    }
+    write_u16(buffer, 2, FLAG_RESPONSE | FLAG_AUTHORITATIVE);
+    write_u16(buffer, 6, count);
+    Some(len)
+}
+
+fn txt_entry(w: &mut Writer, key: &str, value: &str) -> Option<()> {
+    w.bytes(&[(key.len() + 1 + value.len()) as u8])?;
+    w.bytes(key.as_bytes())?;
+    w.bytes(b"=")?;
+    w.bytes(value.as_bytes())
+}
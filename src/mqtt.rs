@@ -0,0 +1,432 @@
+use crate::color_storage::SaveSignal;
+use crate::device_name::{DeviceName, SharedDeviceName};
+use crate::http::MAX_LISTENERS;
+use crate::light_state::{LightState, COLD_TEMPERATURE, WARM_TEMPERATURE};
+use crate::mqtt_config::{MqttConfig, SharedMqttConfig};
+use crate::transition::NextTransition;
+use crate::value_synchronizer::{ValueSynchronizer, Watcher};
+use crate::ESP_APP_DESC;
+use core::fmt::Write as _;
+use embassy_executor::Spawner;
+use embassy_futures::select::{select, select4, Either, Either4};
+use embassy_net::dns::DnsQueryType;
+use embassy_net::tcp::TcpSocket;
+use embassy_net::{IpAddress, Stack};
+use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+use embassy_time::{Duration, Instant, Timer};
+use embedded_io_async::Write;
+use esp_hal::efuse::Efuse;
+use heapless::String;
+use lightbringer_core::mqtt::{
+    connack_code, parse_packet, parse_publish, puback_packet, publish_packet, subscribe_packet,
+    Connect, FrameError, Packet, Publish, DISCONNECT, MAX_PACKET_LEN, PINGREQ,
+};
+use serde::{Deserialize, Serialize};
+
+const KEEP_ALIVE_SECS: u16 = 60;
+const PING_INTERVAL: Duration = Duration::from_secs(30);
+const RETRY_DELAY: Duration = Duration::from_secs(10);
+/// Changes within this time are published together, so dragging a slider doesn't flood the broker
+const STATE_DEBOUNCE: Duration = Duration::from_millis(250);
+const DISCOVERY_PREFIX: &str = "homeassistant";
+const TOPIC_PREFIX: &str = "lightbringer";
+
+type Topic = String<64>;
+/// `lightbringer-` followed by the MAC address in hex
+type DeviceId = String<25>;
+
+/// The shared state the MQTT client operates on
+pub struct MqttContext {
+    pub data: &'static ValueSynchronizer<MAX_LISTENERS, NoopRawMutex, LightState>,
+    pub transition: &'static NextTransition,
+    pub save: &'static SaveSignal,
+    pub name: &'static SharedDeviceName,
+    pub config: &'static SharedMqttConfig,
+}
+
+#[derive(Debug)]
+enum MqttError {
+    Dns,
+    Connect(embassy_net::tcp::ConnectError),
+    Io(embassy_net::tcp::Error),
+    /// The broker refused the connection with this return code
+    Refused(u8),
+    Frame(FrameError),
+    Protocol(&'static str),
+}
+
+impl From<embassy_net::tcp::Error> for MqttError {
+    fn from(value: embassy_net::tcp::Error) -> Self {
+        Self::Io(value)
+    }
+}
+
+impl From<FrameError> for MqttError {
+    fn from(value: FrameError) -> Self {
+        Self::Frame(value)
+    }
+}
+
+/// Starts a client that exposes the light to Home Assistant, when a broker is configured
+pub fn setup_mqtt(stack: Stack<'static>, ctx: MqttContext, spawner: Spawner) {
+    spawner.must_spawn(mqtt_task(stack, ctx));
+}
+
+#[embassy_executor::task]
+async fn mqtt_task(stack: Stack<'static>, ctx: MqttContext) -> ! {
+    let id = device_id(Efuse::read_base_mac_address());
+    let mut config_watcher = ctx.config.watch();
+    loop {
+        let config = ctx.config.read_clone();
+        if !config.is_enabled() {
+            config_watcher.read().await;
+            continue;
+        }
+
+        stack.wait_config_up().await;
+        let name = ctx.name.read_clone();
+        log::info!(
+            "Connecting to MQTT broker {}:{}...",
+            config.host,
+            config.port
+        );
+        match run_session(stack, &ctx, &config, &mut config_watcher, &name, &id).await {
+            Ok(()) => log::info!("MQTT config changed, reconnecting..."),
+            Err(e) => {
+                log::info!("MQTT connection failed: {e:?}");
+                select(Timer::after(RETRY_DELAY), config_watcher.read()).await;
+            }
+        }
+    }
+}
+
+/// Identifies the lamp to Home Assistant, so the entity keeps its history when the lamp is renamed
+fn device_id(mac: [u8; 6]) -> DeviceId {
+    let mut id = DeviceId::new();
+    write!(id, "{TOPIC_PREFIX}-").unwrap();
+    for byte in mac {
+        write!(id, "{byte:02x}").unwrap();
+    }
+    id
+}
+
+/// Topics of the lamp, which are based on its name
+struct Topics {
+    discovery: Topic,
+    state: Topic,
+    command: Topic,
+    availability: Topic,
+}
+
+impl Topics {
+    fn new(name: &str) -> Self {
+        let topic = |suffix: &str| {
+            let mut topic = Topic::new();
+            write!(topic, "{TOPIC_PREFIX}/{name}/{suffix}").unwrap();
+            topic
+        };
+        let mut discovery = Topic::new();
+        write!(discovery, "{DISCOVERY_PREFIX}/light/{name}/config").unwrap();
+        Self {
+            discovery,
+            state: topic("state"),
+            command: topic("set"),
+            availability: topic("availability"),
+        }
+    }
+}
+
+/// Runs a connection until it fails or the config or name changes
+async fn run_session(
+    stack: Stack<'static>,
+    ctx: &MqttContext,
+    config: &MqttConfig,
+    config_watcher: &mut Watcher<'static, 1, NoopRawMutex, MqttConfig>,
+    name: &DeviceName,
+    id: &str,
+) -> Result<(), MqttError> {
+    let address = match config.host.parse::<core::net::IpAddr>() {
+        Ok(address) => IpAddress::from(address),
+        Err(_) => *stack
+            .dns_query(&config.host, DnsQueryType::A)
+            .await
+            .map_err(|_| MqttError::Dns)?
+            .first()
+            .ok_or(MqttError::Dns)?,
+    };
+
+    let mut rx_buffer = [0; MAX_PACKET_LEN];
+    let mut tx_buffer = [0; MAX_PACKET_LEN];
+    let mut socket = TcpSocket::new(stack, &mut rx_buffer, &mut tx_buffer);
+    socket.set_timeout(Some(Duration::from_secs(2 * KEEP_ALIVE_SECS as u64)));
+    socket
+        .connect((address, config.port))
+        .await
+        .map_err(MqttError::Connect)?;
+
+    let topics = Topics::new(name);
+    socket
+        .write_all(&connect_packet(config, name, &topics)?)
+        .await?;
+    let mut received = [0; MAX_PACKET_LEN];
+    let mut filled = 0;
+    let code = loop {
+        filled += read_some(&mut socket, &mut received[filled..]).await?;
+        if let Some((first, body, _)) = parse_packet(&received[..filled])? {
+            break connack_code(first, body);
+        }
+    };
+    match code {
+        Some(0) => {}
+        Some(code) => return Err(MqttError::Refused(code)),
+        None => return Err(MqttError::Protocol("Expected CONNACK")),
+    }
+    // Nothing else is sent before the CONNACK was handled
+    filled = 0;
+    log::info!("Connected to MQTT broker");
+
+    let discovery = discovery_payload(name, id, &topics)?;
+    socket
+        .write_all(&publish_packet(&topics.discovery, &discovery, true)?)
+        .await?;
+    socket
+        .write_all(&publish_packet(&topics.availability, b"online", true)?)
+        .await?;
+    socket
+        .write_all(&subscribe_packet(&topics.command)?)
+        .await?;
+
+    let mut watcher = ctx.data.watch();
+    let mut state = ctx.data.read_clone();
+    let mut last_on_brightness = match state.brightness {
+        0 => u16::MAX,
+        brightness => brightness,
+    };
+    let mut name_watcher = ctx.name.watch();
+    let mut next_ping = Instant::now() + PING_INTERVAL;
+    // The state is published when it's due
+    let mut publish_at = Some(Instant::now());
+    loop {
+        // Handle all complete packets
+        while let Some((first, body, len)) = parse_packet(&received[..filled])? {
+            if let Some(publish) = parse_publish(first, body) {
+                handle_publish(ctx, &topics, &publish, last_on_brightness);
+                if let Some(packet_id) = publish.ack {
+                    socket.write_all(&puback_packet(packet_id)).await?;
+                }
+            }
+            received.copy_within(len..filled, 0);
+            filled -= len;
+        }
+        if filled == received.len() {
+            return Err(FrameError::TooLarge.into());
+        }
+
+        let changed = select(config_watcher.read(), name_watcher.read());
+        let deadline = publish_at.map_or(next_ping, |at| at.min(next_ping));
+        let event = select4(
+            read_some(&mut socket, &mut received[filled..]),
+            watcher.read(),
+            Timer::at(deadline),
+            changed,
+        )
+        .await;
+        match event {
+            Either4::First(len) => filled += len?,
+            Either4::Second(new_state) => {
+                state = new_state;
+                if state.brightness != 0 {
+                    last_on_brightness = state.brightness;
+                }
+                publish_at.get_or_insert(Instant::now() + STATE_DEBOUNCE);
+            }
+            Either4::Third(()) => {
+                let now = Instant::now();
+                if publish_at.is_some_and(|at| at <= now) {
+                    publish_at = None;
+                    socket
+                        .write_all(&publish_packet(
+                            &topics.state,
+                            &state_payload(state)?,
+                            true,
+                        )?)
+                        .await?;
+                }
+                if next_ping <= now {
+                    socket.write_all(&[PINGREQ, 0]).await?;
+                    next_ping = now + PING_INTERVAL;
+                }
+            }
+            Either4::Fourth(Either::First(_)) => {
+                socket
+                    .write_all(&publish_packet(&topics.availability, b"offline", true)?)
+                    .await?;
+                // The broker only publishes the will when the connection is lost
+                socket.write_all(&[DISCONNECT, 0]).await?;
+                socket.close();
+                socket.flush().await.ok();
+                return Ok(());
+            }
+            Either4::Fourth(Either::Second(_)) => {
+                // The topics are named after the lamp, so the retained messages of the old name
+                // are removed, the next session announces the lamp under its new name
+                for topic in [&topics.discovery, &topics.state, &topics.availability] {
+                    socket.write_all(&publish_packet(topic, b"", true)?).await?;
+                }
+                socket.write_all(&[DISCONNECT, 0]).await?;
+                socket.close();
+                socket.flush().await.ok();
+                return Ok(());
+            }
+        }
+    }
+}
+
+async fn read_some(socket: &mut TcpSocket<'_>, buffer: &mut [u8]) -> Result<usize, MqttError> {
+    match socket.read(buffer).await? {
+        0 => Err(MqttError::Protocol("Connection closed by broker")),
+        len => Ok(len),
+    }
+}
+
+fn connect_packet(config: &MqttConfig, name: &str, topics: &Topics) -> Result<Packet, MqttError> {
+    let connect = Connect {
+        client_id: name,
+        keep_alive_secs: KEEP_ALIVE_SECS,
+        // Home Assistant marks the light unavailable when the connection is lost
+        will: (&topics.availability, "offline"),
+        credentials: (!config.username.is_empty())
+            .then_some((config.username.as_str(), config.password.as_str())),
+    };
+    Ok(connect.encode()?)
+}
+
+#[derive(Serialize)]
+struct Discovery<'a> {
+    /// `None` names the entity after the device
+    name: Option<&'a str>,
+    unique_id: &'a str,
+    schema: &'static str,
+    command_topic: &'a str,
+    state_topic: &'a str,
+    availability_topic: &'a str,
+    brightness: bool,
+    supported_color_modes: [&'static str; 1],
+    color_temp_kelvin: bool,
+    min_kelvin: u16,
+    max_kelvin: u16,
+    device: DiscoveryDevice<'a>,
+}
+
+#[derive(Serialize)]
+struct DiscoveryDevice<'a> {
+    identifiers: [&'a str; 1],
+    name: &'a str,
+    manufacturer: &'static str,
+    sw_version: &'static str,
+}
+
+/// Config of a colour temperature light using the JSON schema of Home Assistant
+fn discovery_payload(name: &str, id: &str, topics: &Topics) -> Result<Packet, MqttError> {
+    let discovery = Discovery {
+        name: None,
+        unique_id: id,
+        schema: "json",
+        command_topic: &topics.command,
+        state_topic: &topics.state,
+        availability_topic: &topics.availability,
+        brightness: true,
+        supported_color_modes: ["color_temp"],
+        color_temp_kelvin: true,
+        min_kelvin: WARM_TEMPERATURE,
+        max_kelvin: COLD_TEMPERATURE,
+        device: DiscoveryDevice {
+            identifiers: [id],
+            name,
+            manufacturer: "Lightbringer",
+            sw_version: ESP_APP_DESC.version(),
+        },
+    };
+    to_json(&discovery)
+}
+
+#[derive(Serialize)]
+struct StateMessage {
+    state: Power,
+    brightness: u8,
+    color_mode: &'static str,
+    color_temp: u16,
+}
+
+#[derive(Copy, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "UPPERCASE")]
+enum Power {
+    On,
+    Off,
+}
+
+fn state_payload(state: LightState) -> Result<Packet, MqttError> {
+    to_json(&StateMessage {
+        state: if state.brightness == 0 {
+            Power::Off
+        } else {
+            Power::On
+        },
+        brightness: ((state.brightness as u32 + 128) / 257) as u8,
+        color_mode: "color_temp",
+        color_temp: state.temperature,
+    })
+}
+
+fn to_json(value: &impl Serialize) -> Result<Packet, MqttError> {
+    let mut payload = Packet::new();
+    payload.resize_default(MAX_PACKET_LEN).unwrap();
+    let len = serde_json_core::to_slice(value, &mut payload)
+        .map_err(|_| MqttError::Protocol("Payload too large"))?;
+    payload.truncate(len);
+    Ok(payload)
+}
+
+/// A command from Home Assistant, where `color_temp` is in Kelvin and `transition` in seconds
+#[derive(Deserialize)]
+struct Command {
+    state: Option<Power>,
+    brightness: Option<u8>,
+    color_temp: Option<u16>,
+    transition: Option<f32>,
+}
+
+impl Command {
+    fn apply(&self, state: &mut LightState, last_on_brightness: u16) {
+        if let Some(temperature) = self.color_temp {
+            *state = LightState::new(state.brightness, temperature);
+        }
+        match (self.state, self.brightness) {
+            (Some(Power::Off), _) => state.brightness = 0,
+            (_, Some(brightness)) => state.brightness = brightness as u16 * 257,
+            // Turning on restores the brightness from before turning off
+            (Some(Power::On), None) if state.brightness == 0 => {
+                state.brightness = last_on_brightness
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Applies a command received on the command topic
+fn handle_publish(ctx: &MqttContext, topics: &Topics, publish: &Publish, last_on_brightness: u16) {
+    if publish.topic != topics.command.as_bytes() {
+        return;
+    }
+    let Ok((command, _)) = serde_json_core::from_slice::<Command>(publish.payload) else {
+        log::info!("Received invalid MQTT command");
+        return;
+    };
+
+    if let Some(seconds) = command.transition {
+        ctx.transition.set((seconds.max(0.0) * 1000.0) as u32);
+    }
+    ctx.data
+        .update(|state| command.apply(state, last_on_brightness));
+    ctx.save.signal(());
+}
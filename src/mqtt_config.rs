@@ -0,0 +1,91 @@
+use crate::journal::{read_str, store_record, write_str, Journal, RecordKind, SharedJournal};
+use crate::value_synchronizer::ValueSynchronizer;
+use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+use heapless::String;
+
+pub const MAX_HOST_LEN: usize = 64;
+pub const MAX_USERNAME_LEN: usize = 32;
+pub const MAX_PASSWORD_LEN: usize = 64;
+pub const DEFAULT_PORT: u16 = 1883;
+const MQTT_CONFIG_LEN: usize = 2 + 3 + MAX_HOST_LEN + MAX_USERNAME_LEN + MAX_PASSWORD_LEN;
+const RECORD_VERSION: u8 = 1;
+
+/// The config is only watched by the MQTT task
+pub type SharedMqttConfig = ValueSynchronizer<1, NoopRawMutex, MqttConfig>;
+
+/// Broker to connect to, the client is disabled when no host is set
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct MqttConfig {
+    /// Hostname or IP address
+    pub host: String<MAX_HOST_LEN>,
+    pub port: u16,
+    /// Credentials are only sent when the username is not empty
+    pub username: String<MAX_USERNAME_LEN>,
+    pub password: String<MAX_PASSWORD_LEN>,
+}
+
+impl Default for MqttConfig {
+    fn default() -> Self {
+        Self {
+            host: String::new(),
+            port: DEFAULT_PORT,
+            username: String::new(),
+            password: String::new(),
+        }
+    }
+}
+
+impl MqttConfig {
+    pub fn is_enabled(&self) -> bool {
+        !self.host.is_empty()
+    }
+
+    /// Reads `[PORT: 2, HOST_LEN, HOST.., USERNAME_LEN, USERNAME.., PASSWORD_LEN, PASSWORD..]`
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let port = u16::from_le_bytes(bytes.get(..2)?.try_into().ok()?);
+        let (host, rest) = read_str(&bytes[2..])?;
+        let (username, rest) = read_str(rest)?;
+        let (password, _) = read_str(rest)?;
+        Some(Self {
+            host: host.try_into().ok()?,
+            port,
+            username: username.try_into().ok()?,
+            password: password.try_into().ok()?,
+        })
+    }
+
+    pub fn into_bytes(self) -> heapless::Vec<u8, MQTT_CONFIG_LEN> {
+        let mut bytes = heapless::Vec::new();
+        bytes.extend_from_slice(&self.port.to_le_bytes()).unwrap();
+        write_str(&mut bytes, &self.host);
+        write_str(&mut bytes, &self.username);
+        write_str(&mut bytes, &self.password);
+        bytes
+    }
+}
+
+pub fn read_mqtt_config(journal: &mut Journal) -> MqttConfig {
+    let mut buffer = [0; MQTT_CONFIG_LEN];
+    let config = match journal.read_latest(RecordKind::MqttConfig, &mut buffer) {
+        None => None,
+        Some((RECORD_VERSION, len)) => MqttConfig::from_bytes(&buffer[..len]),
+        Some((version, _)) => {
+            log::warn!("Unknown MQTT config record version {version}");
+            None
+        }
+    };
+    config.unwrap_or_default()
+}
+
+pub fn store_mqtt_config(journal: &SharedJournal, config: MqttConfig) -> bool {
+    let stored = store_record(
+        journal,
+        RecordKind::MqttConfig,
+        RECORD_VERSION,
+        &config.into_bytes(),
+    );
+    if stored {
+        log::info!("MQTT config updated");
+    }
+    stored
+}
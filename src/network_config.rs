@@ -0,0 +1,105 @@
+use crate::journal::{store_record, Journal, RecordKind, SharedJournal};
+use crate::value_synchronizer::ValueSynchronizer;
+use embassy_net::{Ipv4Address, Ipv4Cidr, StaticConfigV4};
+use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+
+/// The most DNS servers embassy-net accepts
+pub const MAX_DNS_SERVERS: usize = 3;
+const NETWORK_CONFIG_LEN: usize = 1 + 4 + 1 + 1 + 4 + 1 + 4 * MAX_DNS_SERVERS;
+const RECORD_VERSION: u8 = 1;
+
+/// The settings are only watched by the task that configures the network stack
+pub type SharedIpv4Settings = ValueSynchronizer<1, NoopRawMutex, Ipv4Settings>;
+
+/// How the lamp gets its IPv4 address
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub enum Ipv4Settings {
+    #[default]
+    Dhcp,
+    Static(StaticConfigV4),
+}
+
+impl Ipv4Settings {
+    /// Reads `[0]` for DHCP or
+    /// `[1, ADDRESS: 4, PREFIX_LEN, HAS_GATEWAY, GATEWAY: 4, DNS_COUNT, DNS: 4..]` for a static address
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let address = |pos: usize| -> Option<Ipv4Address> {
+            let octets: [u8; 4] = bytes.get(pos..pos + 4)?.try_into().ok()?;
+            Some(Ipv4Address::from(octets))
+        };
+        match *bytes.first()? {
+            0 => Some(Self::Dhcp),
+            1 => {
+                // `Ipv4Cidr::new` panics on prefixes above 32
+                let prefix_len = *bytes.get(5)?;
+                if !(1..=32).contains(&prefix_len) {
+                    return None;
+                }
+                let cidr = Ipv4Cidr::new(address(1)?, prefix_len);
+                let gateway = match *bytes.get(6)? {
+                    0 => None,
+                    _ => Some(address(7)?),
+                };
+                let mut dns_servers = heapless::Vec::new();
+                for i in 0..*bytes.get(11)? as usize {
+                    dns_servers.push(address(12 + 4 * i)?).ok()?;
+                }
+                Some(Self::Static(StaticConfigV4 {
+                    address: cidr,
+                    gateway,
+                    dns_servers,
+                }))
+            }
+            _ => None,
+        }
+    }
+
+    pub fn into_bytes(self) -> heapless::Vec<u8, NETWORK_CONFIG_LEN> {
+        let mut bytes = heapless::Vec::new();
+        match self {
+            Self::Dhcp => bytes.push(0).unwrap(),
+            Self::Static(config) => {
+                bytes.push(1).unwrap();
+                bytes
+                    .extend_from_slice(&config.address.address().octets())
+                    .unwrap();
+                bytes.push(config.address.prefix_len()).unwrap();
+                bytes.push(config.gateway.is_some() as u8).unwrap();
+                bytes
+                    .extend_from_slice(&config.gateway.unwrap_or(Ipv4Address::UNSPECIFIED).octets())
+                    .unwrap();
+                bytes.push(config.dns_servers.len() as u8).unwrap();
+                for server in &config.dns_servers {
+                    bytes.extend_from_slice(&server.octets()).unwrap();
+                }
+            }
+        }
+        bytes
+    }
+}
+
+pub fn read_ipv4_settings(journal: &mut Journal) -> Ipv4Settings {
+    let mut buffer = [0; NETWORK_CONFIG_LEN];
+    let settings = match journal.read_latest(RecordKind::NetworkConfig, &mut buffer) {
+        None => None,
+        Some((RECORD_VERSION, len)) => Ipv4Settings::from_bytes(&buffer[..len]),
+        Some((version, _)) => {
+            log::warn!("Unknown network config record version {version}");
+            None
+        }
+    };
+    settings.unwrap_or_default()
+}
+
+pub fn store_ipv4_settings(journal: &SharedJournal, settings: Ipv4Settings) -> bool {
+    let stored = store_record(
+        journal,
+        RecordKind::NetworkConfig,
+        RECORD_VERSION,
+        &settings.into_bytes(),
+    );
+    if stored {
+        log::info!("Network config updated");
+    }
+    stored
+}
@@ -0,0 +1,278 @@
+use crate::firmware_signature::{SignedImageReader, SIGNATURE_LEN};
+use crate::ota_data::{OtaData, SlotState};
+use crate::partitions::{Partition, ReadWritePartitionError};
+use core::cell::Cell;
+use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_time::{Duration, Timer};
+use embedded_io_async::{ErrorType, Read};
+use esp_hal::system::software_reset;
+use esp_ota_nostd::get_booted_partition;
+use esp_partition_table::{AppPartitionType, PartitionType};
+use esp_storage::FlashStorage;
+use picoserve::request::Request;
+use picoserve::response::{IntoResponse, Json, ResponseWriter, StatusCode};
+use picoserve::routing::RequestHandlerService;
+use picoserve::ResponseSent;
+use serde::Serialize;
+
+/// Time for the response to reach the browser before the lamp resets
+const RESET_DELAY: Duration = Duration::from_secs(1);
+const SECTOR_SIZE: u32 = 0x1000;
+/// Flash writes are made in multiples of this
+const WRITE_ALIGN: usize = 4;
+
+/// Progress of the current or last update, polled by the OTA page
+pub type SharedOtaProgress = Mutex<NoopRawMutex, Cell<OtaProgress>>;
+
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OtaState {
+    #[default]
+    Idle,
+    Writing,
+    Installed,
+    Failed,
+}
+
+#[derive(Copy, Clone, Debug, Default, Serialize)]
+pub struct OtaProgress {
+    pub state: OtaState,
+    /// Bytes of the upload, including the signature, that were written so far
+    pub written: usize,
+    pub total: usize,
+}
+
+fn update_progress(progress: &SharedOtaProgress, f: impl FnOnce(&mut OtaProgress)) {
+    progress.lock(|progress| {
+        let mut value = progress.get();
+        f(&mut value);
+        progress.set(value);
+    })
+}
+
+/// Why an update was not installed, the lamp keeps running the current firmware
+#[derive(Copy, Clone, Debug)]
+pub enum OtaError {
+    /// Another update is still being written
+    Busy,
+    /// Not signed with our key, or the upload was cut short
+    BadImage,
+    /// The image doesn't fit in the partition it would be written to
+    TooLarge,
+    Flash,
+}
+
+impl OtaError {
+    pub fn status(self) -> StatusCode {
+        match self {
+            OtaError::Busy => StatusCode::CONFLICT,
+            OtaError::BadImage => StatusCode::BAD_REQUEST,
+            OtaError::TooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+            OtaError::Flash => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn response(self) -> OtaResponse {
+        let (result, message) = match self {
+            OtaError::Busy => ("busy", "Another update is in progress"),
+            OtaError::BadImage => ("bad_image", "Firmware is not signed or incomplete"),
+            OtaError::TooLarge => ("too_large", "Firmware is larger than the OTA partition"),
+            OtaError::Flash => ("flash_error", "Writing the firmware to flash failed"),
+        };
+        OtaResponse { result, message }
+    }
+}
+
+#[derive(Serialize)]
+struct OtaResponse {
+    /// One of `updated`, `busy`, `bad_image`, `too_large` or `flash_error`
+    result: &'static str,
+    message: &'static str,
+}
+
+/// The OTA slot that is not running and its partition, which the update is written to.
+/// Finding the booted partition only reads the partition table, all writes go through
+/// `Partition`.
+fn target_partition() -> Option<(u8, Partition)> {
+    let booted = get_booted_partition(&mut FlashStorage::new())
+        .inspect_err(|error| log::warn!("Finding the booted partition failed: {error:?}"))
+        .ok()?;
+    let slot = if booted.name() == "ota_0" { 1 } else { 0 };
+    let partition = Partition::open_type(PartitionType::App(AppPartitionType::Ota(slot)))
+        .inspect_err(|error| log::warn!("Finding OTA partition {slot} failed: {error:?}"))
+        .ok()?;
+    Some((slot, partition))
+}
+
+/// Size of the partition that is not running, which the update is written to
+pub fn target_partition_size() -> Option<usize> {
+    target_partition().map(|(_, partition)| partition.size() as usize)
+}
+
+/// Counts the bytes that are written
+struct ProgressReader<'a, R> {
+    reader: R,
+    progress: &'a SharedOtaProgress,
+}
+
+impl<R: Read> ErrorType for ProgressReader<'_, R> {
+    type Error = R::Error;
+}
+
+impl<R: Read> Read for ProgressReader<'_, R> {
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        let read = self.reader.read(buf).await?;
+        update_progress(self.progress, |progress| progress.written += read);
+        Ok(read)
+    }
+}
+
+/// Writes a signed image to the other OTA slot, which is only booted next once the signature
+/// after the image was verified
+async fn install<R: Read>(
+    reader: R,
+    len: usize,
+    progress: &SharedOtaProgress,
+) -> Result<(), OtaError> {
+    let (slot, mut partition) = target_partition().ok_or(OtaError::Flash)?;
+    let image_len = len.saturating_sub(SIGNATURE_LEN);
+    let partition_size = partition.size() as usize;
+    if image_len > partition_size {
+        log::warn!("Firmware of {image_len} bytes doesn't fit in {partition_size} bytes");
+        return Err(OtaError::TooLarge);
+    }
+    let flash_error = |error: ReadWritePartitionError| {
+        log::warn!("Writing the firmware failed: {error:?}");
+        OtaError::Flash
+    };
+    let erase_len = (image_len as u32).next_multiple_of(SECTOR_SIZE);
+    partition.erase(0, erase_len).map_err(flash_error)?;
+
+    let reader = ProgressReader { reader, progress };
+    let mut reader = SignedImageReader::new(reader, len);
+    let mut buffer = [0; 1024];
+    let mut offset = 0;
+    loop {
+        // Fill the buffer, the reader ends after the image once its signature is valid
+        let mut filled = 0;
+        while filled < buffer.len() {
+            match reader.read(&mut buffer[filled..]).await {
+                Ok(0) => break,
+                Ok(read) => filled += read,
+                Err(error) => {
+                    log::warn!("OTA update failed: {error:?}");
+                    return Err(if reader.is_rejected() {
+                        OtaError::BadImage
+                    } else {
+                        OtaError::Flash
+                    });
+                }
+            }
+        }
+        // Padding with the erased value leaves the flash after the image untouched
+        let end = filled.next_multiple_of(WRITE_ALIGN);
+        buffer[filled..end].fill(0xFF);
+        partition
+            .write(offset, &buffer[..end])
+            .map_err(flash_error)?;
+        offset += end as u32;
+        if filled < buffer.len() {
+            break;
+        }
+    }
+
+    // Only a complete image with a valid signature is made bootable
+    if !reader.is_verified() {
+        return Err(OtaError::BadImage);
+    }
+    OtaData::open()
+        .and_then(|mut ota_data| ota_data.activate(slot, SlotState::New))
+        .map_err(flash_error)
+}
+
+/// Installs a signed image of `len` bytes, unless another update is in progress
+pub async fn update_firmware<R: Read>(
+    reader: R,
+    len: usize,
+    progress: &SharedOtaProgress,
+) -> Result<(), OtaError> {
+    let busy = progress.lock(|progress| {
+        let busy = progress.get().state == OtaState::Writing;
+        if !busy {
+            progress.set(OtaProgress {
+                state: OtaState::Writing,
+                written: 0,
+                total: len,
+            });
+        }
+        busy
+    });
+    if busy {
+        return Err(OtaError::Busy);
+    }
+
+    log::info!("Starting OTA update of {len} bytes...");
+    let guard = WritingGuard(progress);
+    let result = install(reader, len, progress).await;
+    core::mem::forget(guard);
+    update_progress(progress, |progress| {
+        progress.state = match result {
+            Ok(()) => OtaState::Installed,
+            Err(_) => OtaState::Failed,
+        }
+    });
+    result
+}
+
+/// Marks an update as failed when it is dropped while writing, such as when the upload's
+/// connection closes, so the next update isn't refused as busy
+struct WritingGuard<'a>(&'a SharedOtaProgress);
+
+impl Drop for WritingGuard<'_> {
+    fn drop(&mut self) {
+        log::warn!("OTA update was aborted");
+        update_progress(self.0, |progress| progress.state = OtaState::Failed);
+    }
+}
+
+/// Restarts into the new firmware, after giving pending responses time to be sent
+pub async fn restart() -> ! {
+    log::info!("OTA update finished, resetting...");
+    Timer::after(RESET_DELAY).await;
+    software_reset();
+}
+
+pub struct OtaHandler {
+    pub progress: &'static SharedOtaProgress,
+}
+
+impl RequestHandlerService<()> for OtaHandler {
+    async fn call_request_handler_service<R: Read, W: ResponseWriter<Error = R::Error>>(
+        &self,
+        _state: &(),
+        _path_parameters: (),
+        mut request: Request<'_, R>,
+        response_writer: W,
+    ) -> Result<ResponseSent, W::Error> {
+        let len = request.body_connection.content_length();
+        let reader = request.body_connection.body().reader();
+        let result = update_firmware(reader, len, self.progress).await;
+        let connection = request.body_connection.finalize().await?;
+        match result {
+            Ok(()) => {
+                let response = OtaResponse {
+                    result: "updated",
+                    message: "Firmware installed, restarting",
+                };
+                Json(response).write_to(connection, response_writer).await?;
+                restart().await
+            }
+            Err(error) => {
+                (error.status(), Json(error.response()))
+                    .write_to(connection, response_writer)
+                    .await
+            }
+        }
+    }
+}
@@ -0,0 +1,66 @@
+//! Reads and updates the `otadata` partition, which tells the bootloader which OTA slot to boot
+
+use crate::partitions::{Partition, ReadWritePartitionError};
+use esp_partition_table::{DataPartitionType, PartitionType};
+use lightbringer_core::ota_data::{activation_entry, active_entry, slot_states, ENTRY_LEN};
+
+pub use lightbringer_core::ota_data::{OtaEntry, SlotState};
+
+const SECTOR_SIZE: u32 = 0x1000;
+
+pub struct OtaData {
+    partition: Partition,
+}
+
+impl OtaData {
+    pub fn open() -> Result<Self, ReadWritePartitionError> {
+        let partition = Partition::open_type(PartitionType::Data(DataPartitionType::Ota))?;
+        Ok(Self { partition })
+    }
+
+    /// The entries of both sectors, `None` when a sector is empty or corrupt
+    pub fn entries(&mut self) -> Result<[Option<OtaEntry>; 2], ReadWritePartitionError> {
+        let mut entries = [None; 2];
+        for (sector, entry) in (0..).zip(&mut entries) {
+            let mut raw = [0; ENTRY_LEN];
+            self.partition.read(sector * SECTOR_SIZE, &mut raw)?;
+            *entry = OtaEntry::parse(sector, raw);
+        }
+        Ok(entries)
+    }
+
+    /// The entry the bootloader follows, `None` when it boots the first app partition
+    pub fn active(&mut self) -> Result<Option<OtaEntry>, ReadWritePartitionError> {
+        Ok(active_entry(self.entries()?))
+    }
+
+    /// Makes the bootloader boot `slot` next, with a newer entry than the ones it already holds
+    pub fn activate(&mut self, slot: u8, state: SlotState) -> Result<(), ReadWritePartitionError> {
+        let (sector, raw) = activation_entry(self.entries()?, slot, state);
+        self.write_sector(sector, &raw)
+    }
+
+    /// State of each OTA slot according to its newest entry, `None` when no entry points to it
+    pub fn slot_states(&mut self) -> Result<[Option<SlotState>; 2], ReadWritePartitionError> {
+        Ok(slot_states(self.entries()?))
+    }
+
+    /// Changes the state of an entry, which rewrites its sector
+    pub fn set_state(
+        &mut self,
+        entry: &OtaEntry,
+        state: SlotState,
+    ) -> Result<(), ReadWritePartitionError> {
+        self.write_sector(entry.sector, &entry.with_state(state))
+    }
+
+    fn write_sector(
+        &mut self,
+        sector: u32,
+        raw: &[u8; ENTRY_LEN],
+    ) -> Result<(), ReadWritePartitionError> {
+        let start = sector * SECTOR_SIZE;
+        self.partition.erase(start, start + SECTOR_SIZE)?;
+        self.partition.write(start, raw)
+    }
+}
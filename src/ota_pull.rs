@@ -0,0 +1,211 @@
+use crate::http_client::{self, HttpError, Url, MAX_URL_LEN};
+use crate::journal::{store_record, Journal, RecordKind, SharedJournal};
+use crate::ota::{restart, update_firmware, OtaError, SharedOtaProgress};
+use crate::value_synchronizer::ValueSynchronizer;
+use crate::ESP_APP_DESC;
+use embassy_executor::Spawner;
+use embassy_futures::select::{select3, Either3};
+use embassy_net::Stack;
+use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+use embassy_sync::signal::Signal;
+use embassy_time::{Duration, Instant, Timer};
+use embedded_io_async::Read;
+use heapless::String;
+use lightbringer_core::version::is_newer;
+use serde::Deserialize;
+
+/// Give the network time to settle, and the new firmware time to be accepted, before checking
+const FIRST_CHECK_DELAY: Duration = Duration::from_secs(5 * 60);
+const CHECK_INTERVAL: Duration = Duration::from_secs(60 * 60);
+const MAX_MANIFEST_LEN: usize = 512;
+const RX_BUFFER_LEN: usize = 4096;
+const RECORD_VERSION: u8 = 1;
+const REJECTED_RECORD_VERSION: u8 = 1;
+const MAX_VERSION_LEN: usize = 32;
+
+/// Manifest to poll for new firmware, automatic updates are disabled when it is empty
+pub type SharedManifestUrl = ValueSynchronizer<1, NoopRawMutex, Url>;
+
+/// Signalled with the URL of an image that should be installed right away
+pub type PullRequest = Signal<NoopRawMutex, Url>;
+
+pub struct OtaPullContext {
+    pub progress: &'static SharedOtaProgress,
+    pub journal: &'static SharedJournal,
+    pub manifest: &'static SharedManifestUrl,
+    pub request: &'static PullRequest,
+}
+
+/// Published by the build server, like `{"version": "0.2.0", "url": "http://builds/lamp.bin"}`
+#[derive(Deserialize)]
+struct Manifest {
+    version: String<MAX_VERSION_LEN>,
+    url: Url,
+}
+
+#[derive(Debug)]
+enum PullError {
+    Http(HttpError),
+    Ota(OtaError),
+    InvalidManifest,
+}
+
+impl From<HttpError> for PullError {
+    fn from(value: HttpError) -> Self {
+        Self::Http(value)
+    }
+}
+
+pub fn setup_ota_pull(stack: Stack<'static>, ctx: OtaPullContext, spawner: Spawner) {
+    spawner.must_spawn(ota_pull_task(stack, ctx));
+}
+
+#[embassy_executor::task]
+async fn ota_pull_task(stack: Stack<'static>, ctx: OtaPullContext) -> ! {
+    let mut manifest_watcher = ctx.manifest.watch();
+    let mut next_check = Instant::now() + FIRST_CHECK_DELAY;
+    loop {
+        let event = select3(
+            ctx.request.wait(),
+            Timer::at(next_check),
+            manifest_watcher.read(),
+        )
+        .await;
+        let result = match event {
+            Either3::First(url) => pull_image(stack, &url, ctx.progress).await,
+            Either3::Second(()) | Either3::Third(_) => {
+                next_check = Instant::now() + CHECK_INTERVAL;
+                let manifest = ctx.manifest.read_clone();
+                if manifest.is_empty() {
+                    continue;
+                }
+                check_manifest(stack, &manifest, &ctx).await
+            }
+        };
+        match result {
+            Ok(true) => restart().await,
+            Ok(false) => {}
+            Err(e) => log::warn!("Pulling firmware failed: {e:?}"),
+        }
+    }
+}
+
+/// Installs the image from the manifest when it is newer, returns whether it was installed
+async fn check_manifest(
+    stack: Stack<'static>,
+    url: &str,
+    ctx: &OtaPullContext,
+) -> Result<bool, PullError> {
+    stack.wait_config_up().await;
+    let mut rx_buffer = [0; MAX_MANIFEST_LEN];
+    let mut tx_buffer = [0; MAX_URL_LEN + 64];
+    let mut response = http_client::get(stack, url, &mut rx_buffer, &mut tx_buffer).await?;
+    let mut body = [0; MAX_MANIFEST_LEN];
+    let mut len = 0;
+    while len < MAX_MANIFEST_LEN {
+        match response
+            .read(&mut body[len..])
+            .await
+            .map_err(HttpError::Io)?
+        {
+            0 => break,
+            read => len += read,
+        }
+    }
+    let (manifest, _) = serde_json_core::from_slice::<Manifest>(&body[..len])
+        .map_err(|_| PullError::InvalidManifest)?;
+    drop(response);
+
+    let current = ESP_APP_DESC.version();
+    if !is_newer(&manifest.version, current) {
+        log::info!(
+            "Firmware {current} is up to date, manifest has {}",
+            manifest.version
+        );
+        return Ok(false);
+    }
+    let rejected = ctx
+        .journal
+        .lock(|journal| read_rejected_version(&mut journal.borrow_mut()));
+    if rejected.as_deref() == Some(&*manifest.version) {
+        log::info!(
+            "Firmware {} was rolled back, waiting for the manifest to change",
+            manifest.version
+        );
+        return Ok(false);
+    }
+    log::info!("Updating from firmware {current} to {}", manifest.version);
+    pull_image(stack, &manifest.url, ctx.progress).await
+}
+
+/// Downloads and installs an image, returns whether it was installed
+async fn pull_image(
+    stack: Stack<'static>,
+    url: &str,
+    progress: &SharedOtaProgress,
+) -> Result<bool, PullError> {
+    stack.wait_config_up().await;
+    log::info!("Downloading firmware from {url}");
+    let mut rx_buffer = [0; RX_BUFFER_LEN];
+    let mut tx_buffer = [0; MAX_URL_LEN + 64];
+    let response = http_client::get(stack, url, &mut rx_buffer, &mut tx_buffer).await?;
+    let len = response
+        .content_length
+        .ok_or(HttpError::Protocol("Missing Content-Length"))?;
+    update_firmware(response, len, progress)
+        .await
+        .map_err(PullError::Ota)?;
+    Ok(true)
+}
+
+pub fn read_manifest_url(journal: &mut Journal) -> Url {
+    let mut buffer = [0; MAX_URL_LEN];
+    let url = match journal.read_latest(RecordKind::ManifestUrl, &mut buffer) {
+        None => None,
+        Some((RECORD_VERSION, len)) => core::str::from_utf8(&buffer[..len])
+            .ok()
+            .and_then(|url| url.try_into().ok()),
+        Some((version, _)) => {
+            log::warn!("Unknown manifest URL record version {version}");
+            None
+        }
+    };
+    url.unwrap_or_default()
+}
+
+pub fn store_manifest_url(journal: &SharedJournal, url: &str) -> bool {
+    let stored = store_record(
+        journal,
+        RecordKind::ManifestUrl,
+        RECORD_VERSION,
+        url.as_bytes(),
+    );
+    if stored {
+        log::info!("Firmware manifest changed to {url:?}");
+    }
+    stored
+}
+
+/// The version of the last firmware that failed its health check
+fn read_rejected_version(journal: &mut Journal) -> Option<String<MAX_VERSION_LEN>> {
+    let mut buffer = [0; MAX_VERSION_LEN];
+    match journal.read_latest(RecordKind::RejectedVersion, &mut buffer)? {
+        (REJECTED_RECORD_VERSION, len) => core::str::from_utf8(&buffer[..len])
+            .ok()
+            .and_then(|version| version.try_into().ok()),
+        (version, _) => {
+            log::warn!("Unknown rejected version record version {version}");
+            None
+        }
+    }
+}
+
+/// Keeps the manifest from installing a firmware again after it was rolled back
+pub fn store_rejected_version(journal: &SharedJournal, version: &str) {
+    store_record(
+        journal,
+        RecordKind::RejectedVersion,
+        REJECTED_RECORD_VERSION,
+        version.as_bytes(),
+    );
+}
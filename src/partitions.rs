@@ -1,12 +1,31 @@
-use crate::partitions::ReadWritePartitionError::{PartitionFoundTwice, PartitionNotFound};
+use crate::partitions::ReadWritePartitionError::{
+    OutOfBounds, PartitionFoundTwice, PartitionNotFound,
+};
+use embedded_storage::nor_flash::{NorFlash, ReadNorFlash};
 use esp_partition_table::{PartitionEntry, PartitionTable, PartitionType, StorageOpError};
-use esp_storage::FlashStorage;
+use esp_storage::{FlashStorage, FlashStorageError};
 
 #[derive(Debug)]
 pub enum ReadWritePartitionError {
     StorageOpError(StorageOpError<FlashStorage>),
+    FlashError(FlashStorageError),
     PartitionNotFound,
     PartitionFoundTwice,
+    /// The access does not fit within the partition
+    OutOfBounds,
+}
+
+impl ReadWritePartitionError {
+    /// Short reason, for reporting over the API
+    pub fn description(&self) -> &'static str {
+        match self {
+            Self::StorageOpError(_) => "reading the partition table failed",
+            Self::FlashError(_) => "flash error",
+            Self::PartitionNotFound => "partition not found",
+            Self::PartitionFoundTwice => "partition found twice",
+            Self::OutOfBounds => "out of space",
+        }
+    }
 }
 
 impl From<StorageOpError<FlashStorage>> for ReadWritePartitionError {
@@ -15,6 +34,12 @@ impl From<StorageOpError<FlashStorage>> for ReadWritePartitionError {
     }
 }
 
+impl From<FlashStorageError> for ReadWritePartitionError {
+    fn from(value: FlashStorageError) -> Self {
+        Self::FlashError(value)
+    }
+}
+
 const CALC_MD5: bool = false;
 
 /// Find partition entry by name
@@ -56,3 +81,62 @@ pub fn find_partition_type(typ: PartitionType) -> Result<PartitionEntry, ReadWri
 
     found_partition.ok_or(PartitionNotFound)
 }
+
+/// Flash access limited to a single partition, offsets are relative to its start
+pub struct Partition {
+    flash: FlashStorage,
+    entry: PartitionEntry,
+}
+
+impl Partition {
+    pub fn open(name: &str) -> Result<Self, ReadWritePartitionError> {
+        Ok(Self::new(find_partition_name(name)?))
+    }
+
+    pub fn open_type(typ: PartitionType) -> Result<Self, ReadWritePartitionError> {
+        Ok(Self::new(find_partition_type(typ)?))
+    }
+
+    fn new(entry: PartitionEntry) -> Self {
+        Self {
+            flash: FlashStorage::new(),
+            entry,
+        }
+    }
+
+    pub fn size(&self) -> u32 {
+        self.entry.size as u32
+    }
+
+    /// Translates a range within the partition to an absolute flash address
+    fn address(&self, offset: u32, len: usize) -> Result<u32, ReadWritePartitionError> {
+        let end = offset.checked_add(len as u32).ok_or(OutOfBounds)?;
+        if end > self.size() {
+            return Err(OutOfBounds);
+        }
+        Ok(self.entry.offset + offset)
+    }
+
+    pub fn read(&mut self, offset: u32, buffer: &mut [u8]) -> Result<(), ReadWritePartitionError> {
+        let address = self.address(offset, buffer.len())?;
+        Ok(self.flash.read(address, buffer)?)
+    }
+
+    /// The range must have been erased before
+    pub fn write(&mut self, offset: u32, data: &[u8]) -> Result<(), ReadWritePartitionError> {
+        let address = self.address(offset, data.len())?;
+        Ok(self.flash.write(address, data)?)
+    }
+
+    /// Erases `from..to`, which must be aligned to sectors
+    pub fn erase(&mut self, from: u32, to: u32) -> Result<(), ReadWritePartitionError> {
+        let len = to.checked_sub(from).ok_or(OutOfBounds)?;
+        let address = self.address(from, len as usize)?;
+        Ok(self.flash.erase(address, address + len)?)
+    }
+
+    /// Erases the whole partition
+    pub fn erase_all(&mut self) -> Result<(), ReadWritePartitionError> {
+        self.erase(0, self.size())
+    }
+}
@@ -0,0 +1,37 @@
+use crate::journal::{store_record, Journal, RecordKind, SharedJournal};
+use core::cell::RefCell;
+use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use lightbringer_core::presets::PRESETS_LEN;
+
+pub use lightbringer_core::presets::{Preset, PresetName, Presets, MAX_PRESETS};
+
+const RECORD_VERSION: u8 = 1;
+
+pub type SharedPresets = Mutex<NoopRawMutex, RefCell<Presets>>;
+
+pub fn read_presets(journal: &mut Journal) -> Presets {
+    let mut buffer = [0; PRESETS_LEN];
+    let presets = match journal.read_latest(RecordKind::Presets, &mut buffer) {
+        None => None,
+        Some((RECORD_VERSION, len)) => Presets::from_bytes(&buffer[..len]),
+        Some((version, _)) => {
+            log::warn!("Unknown presets record version {version}");
+            None
+        }
+    };
+    presets.unwrap_or_default()
+}
+
+pub fn store_presets(journal: &SharedJournal, presets: Presets) -> bool {
+    let stored = store_record(
+        journal,
+        RecordKind::Presets,
+        RECORD_VERSION,
+        &presets.into_bytes(),
+    );
+    if stored {
+        log::info!("Presets updated");
+    }
+    stored
+}
@@ -0,0 +1,116 @@
+use embassy_executor::Spawner;
+use embassy_futures::select::{select, Either};
+use embassy_net::raw::{PacketMetadata, RawSocket};
+use embassy_net::{ConfigV6, Ipv6Address, Ipv6Cidr, Stack, StaticConfigV6};
+use embassy_time::{Duration, Instant, Timer};
+use esp_wifi::wifi::WifiDevice;
+use lightbringer_core::slaac::{
+    address, link_local_address, parse_router_advertisement, router_solicitation, Ipv6Octets,
+    INFINITE_LIFETIME, PREFIX_LEN,
+};
+use smoltcp::wire::{IpProtocol, IpVersion};
+
+/// Advertisements carry options for every prefix, so they can be as large as the minimum MTU
+const MAX_PACKET_LEN: usize = 1280;
+/// Routers that don't answer are asked again after this long
+const SOLICITATION_INTERVAL: Duration = Duration::from_secs(4);
+const MAX_SOLICITATIONS: u32 = 3;
+/// How often the link is checked, so the prefix of a previous network isn't kept
+const LINK_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// The configuration with only a link-local address, used until a router announces a prefix
+pub fn link_local_config(mac: [u8; 6]) -> StaticConfigV6 {
+    static_config(link_local_address(mac), None)
+}
+
+fn static_config(address: Ipv6Octets, gateway: Option<Ipv6Octets>) -> StaticConfigV6 {
+    StaticConfigV6 {
+        address: Ipv6Cidr::new(Ipv6Address::from(address), PREFIX_LEN),
+        gateway: gateway.map(Ipv6Address::from),
+        dns_servers: Default::default(),
+    }
+}
+
+/// Starts forming a global IPv6 address from the prefixes routers announce
+pub fn setup_slaac(stack: Stack<'static>, mac: [u8; 6], spawner: Spawner) {
+    spawner.must_spawn(slaac_task(stack, mac));
+}
+
+/// The stack holds a single IPv6 address, so the global address replaces the link-local one
+/// while it is valid
+#[embassy_executor::task]
+async fn slaac_task(stack: Stack<'static>, mac: [u8; 6]) -> ! {
+    let mut rx_meta = [PacketMetadata::EMPTY; 2];
+    let mut rx_buffer = [0; MAX_PACKET_LEN];
+    let mut tx_meta = [PacketMetadata::EMPTY; 1];
+    let mut tx_buffer = [0; 64];
+    let socket = RawSocket::new::<WifiDevice<'static>>(
+        stack,
+        IpVersion::Ipv6,
+        IpProtocol::Icmpv6,
+        &mut rx_meta,
+        &mut rx_buffer,
+        &mut tx_meta,
+        &mut tx_buffer,
+    );
+
+    let mut packet = [0; MAX_PACKET_LEN];
+    loop {
+        stack.wait_link_up().await;
+        // A prefix of the previous network doesn't route on this one
+        stack.set_config_v6(ConfigV6::Static(link_local_config(mac)));
+
+        // When the global address stops being valid, `None` while there is none
+        let mut valid_until: Option<Instant> = None;
+        let mut solicitations = 0;
+        let mut next_solicitation = Instant::now();
+        while stack.is_link_up() {
+            let soliciting = valid_until.is_none() && solicitations < MAX_SOLICITATIONS;
+            if soliciting && Instant::now() >= next_solicitation {
+                socket.send(&router_solicitation(mac)).await;
+                solicitations += 1;
+                next_solicitation = Instant::now() + SOLICITATION_INTERVAL;
+            }
+
+            let mut wake = Instant::now() + LINK_CHECK_INTERVAL;
+            if let Some(until) = valid_until {
+                wake = wake.min(until);
+            }
+            if soliciting {
+                wake = wake.min(next_solicitation);
+            }
+            let len = match select(socket.recv(&mut packet), Timer::at(wake)).await {
+                Either::First(Ok(len)) => len,
+                Either::First(Err(_)) => continue,
+                Either::Second(()) => {
+                    if valid_until.is_some_and(|until| Instant::now() >= until) {
+                        log::info!("IPv6 prefix expired");
+                        stack.set_config_v6(ConfigV6::Static(link_local_config(mac)));
+                        valid_until = None;
+                        solicitations = 0;
+                    }
+                    continue;
+                }
+            };
+
+            let Some(advertisement) = parse_router_advertisement(&packet[..len]) else {
+                continue;
+            };
+            let Some(prefix) = advertisement.prefix else {
+                continue;
+            };
+            let gateway = (advertisement.router_lifetime > 0).then_some(advertisement.router);
+            let config = static_config(address(&prefix.prefix, mac), gateway);
+            let current = stack.config_v6();
+            if current.as_ref().map(|c| (c.address, c.gateway))
+                != Some((config.address, config.gateway))
+            {
+                stack.set_config_v6(ConfigV6::Static(config));
+            }
+            valid_until = Some(match prefix.valid_lifetime {
+                INFINITE_LIFETIME => Instant::MAX,
+                seconds => Instant::now() + Duration::from_secs(seconds.into()),
+            });
+        }
+    }
+}
@@ -1,27 +1,102 @@
+use crate::api;
+use crate::auth::{AuthLayer, SharedAuthConfig};
+use crate::color_storage::SaveSignal;
+use crate::device_name::SharedDeviceName;
+use crate::dimming_curve::{DimmingCurve, SharedDimmingCurve};
+use crate::health_check::{HealthCheckConfig, RequestServed, ServedLayer, SharedHealthCheckConfig};
 use crate::http::MAX_LISTENERS;
-use crate::light_state::{LightState, LIGHT_STATE_LEN};
+use crate::journal::SharedJournal;
+use crate::light_state::{LightState, CHANNEL_STATE_LEN, LIGHT_STATE_LEN};
+use crate::make_static;
+use crate::mqtt_config::SharedMqttConfig;
+use crate::network_config::SharedIpv4Settings;
+use crate::ota::{OtaHandler, SharedOtaProgress};
+use crate::ota_pull::{PullRequest, SharedManifestUrl};
+use crate::presets::SharedPresets;
 use crate::rotating_logger::RingBufferLogger;
+use crate::transition::NextTransition;
 use crate::value_synchronizer::ValueSynchronizer;
+use crate::wifi::WifiProvisioning;
+use crate::wifi_credentials::WifiCredentials;
+use core::cell::Cell;
 use embassy_futures::select::{select, Either};
+use embassy_net::Stack;
 use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
 use embedded_io_async::{Read, Write};
-use esp_hal::system::software_reset;
-use esp_ota_nostd::ota_begin;
-use esp_storage::FlashStorage;
+use esp_hal::rng::Rng;
+use picoserve::extract::Json;
 use picoserve::request::Request;
 use picoserve::response::ws::{Message, SocketRx, SocketTx, WebSocketCallback};
 use picoserve::response::{IntoResponse, ResponseWriter, WebSocketUpgrade};
-use picoserve::routing::{get, get_service, PathRouter, RequestHandlerService};
+use picoserve::routing::{get, get_service, post, PathRouter, RequestHandlerService};
 use picoserve::{response, ResponseSent, Router};
 
+/// Length of a `[SAVE, BRIGHTNESS, TEMPERATURE]` packet, see `resources/data-format.md`
+const PACKET_LEN: usize = 2 + LIGHT_STATE_LEN;
+/// Length of a `[SAVE, COLD, WARM, X, Y]` packet
+const CHANNEL_PACKET_LEN: usize = 2 + CHANNEL_STATE_LEN;
+
 pub type AppRouter = impl PathRouter;
+pub type PortalRouter = impl PathRouter;
+
+/// Address clients of the access point are sent to by the captive portal checks
+const PROVISIONING_URL: &str = "http://192.168.4.1/provisioning";
+
+/// The shared state the routes operate on
+#[derive(Copy, Clone)]
+pub struct AppContext {
+    pub data: &'static ValueSynchronizer<MAX_LISTENERS, NoopRawMutex, LightState>,
+    pub curve: &'static SharedDimmingCurve,
+    pub transition: &'static NextTransition,
+    pub save: &'static SaveSignal,
+    pub journal: &'static SharedJournal,
+    /// Named light states, stored in `journal`
+    pub presets: &'static SharedPresets,
+    pub provisioning: &'static WifiProvisioning,
+    pub name: &'static SharedDeviceName,
+    pub ipv4: &'static SharedIpv4Settings,
+    pub mqtt: &'static SharedMqttConfig,
+    pub auth: &'static SharedAuthConfig,
+    /// Source of the password salts
+    pub rng: Rng,
+    /// Network stack of the connection to the configured network
+    pub stack: Stack<'static>,
+    pub logger: &'static RingBufferLogger,
+    pub ota: &'static SharedOtaProgress,
+    pub manifest: &'static SharedManifestUrl,
+    pub pull: &'static PullRequest,
+    pub health: &'static SharedHealthCheckConfig,
+    pub served: &'static RequestServed,
+}
 
 #[define_opaque(AppRouter)]
-pub fn make_app(
-    data: &'static ValueSynchronizer<MAX_LISTENERS, NoopRawMutex, LightState>,
-    logger: &'static RingBufferLogger,
-) -> Router<AppRouter> {
-    picoserve::Router::new()
+pub fn make_app(ctx: AppContext) -> Router<AppRouter> {
+    let AppContext {
+        data,
+        curve,
+        transition,
+        save,
+        journal,
+        presets,
+        provisioning,
+        name,
+        ipv4,
+        mqtt,
+        auth,
+        rng,
+        stack,
+        logger,
+        ota,
+        manifest,
+        pull,
+        health,
+        served,
+        ..
+    } = ctx;
+    let position = make_static!(DiskPosition, Mutex::new(Cell::new([0, 0])));
+
+    provisioning_routes(ctx)
         .route(
             "/",
             get_service(response::File::html(include_str!(
@@ -31,21 +106,303 @@ pub fn make_app(
         .route(
             "/ota",
             get_service(response::File::html(include_str!("../resources/ota.html")))
-                .post_service(OtaHandler),
+                .post_service(OtaHandler { progress: ota }),
         )
         .route("/logs", get_service(LogHandler { logger }))
+        .route(
+            "/status",
+            get_service(response::File::html(include_str!(
+                "../resources/status.html"
+            ))),
+        )
+        .route(
+            "/settings",
+            get_service(response::File::html(include_str!(
+                "../resources/settings.html"
+            ))),
+        )
+        .route(
+            "/api/state",
+            get(move || async move { api::get_state(data, curve) })
+                .put(move |Json(update): Json<api::StateUpdate, 0>| async move {
+                    api::update_state(data, curve, transition, save, update, false)
+                })
+                .patch(move |Json(update): Json<api::StateUpdate, 0>| async move {
+                    api::update_state(data, curve, transition, save, update, true)
+                }),
+        )
+        .route(
+            "/api/presets",
+            get(move || async move { api::get_presets(presets) })
+                .post(move |Json(update): Json<api::PresetUpdate, 0>| async move {
+                    api::save_preset(presets, data, journal, update)
+                })
+                .delete(
+                    move |Json(removal): Json<api::PresetRemoval, 0>| async move {
+                        api::remove_preset(presets, journal, removal)
+                    },
+                ),
+        )
+        .route(
+            "/api/presets/recall",
+            post(move |Json(recall): Json<api::PresetRecall, 0>| async move {
+                api::recall_preset(presets, data, curve, transition, save, recall)
+            }),
+        )
+        .route(
+            "/api/curve",
+            get(move || async move { api::get_curve(curve) }).put(
+                move |Json(update): Json<api::CurveUpdate, 0>| async move {
+                    api::update_curve(curve, journal, update)
+                },
+            ),
+        )
+        .route(
+            "/api/device",
+            get(move || async move { api::get_device(name) }).put(
+                move |Json(update): Json<api::DeviceUpdate, 0>| async move {
+                    api::update_device(name, journal, update)
+                },
+            ),
+        )
+        .route(
+            "/api/network/config",
+            get(move || async move { api::get_network_config(ipv4) }).put(
+                move |Json(update): Json<api::NetworkConfigBody, 0>| async move {
+                    api::update_network_config(ipv4, journal, update)
+                },
+            ),
+        )
+        .route(
+            "/api/network/status",
+            get(move || async move { api::get_network_status(stack) }),
+        )
+        .route(
+            "/api/mqtt",
+            get(move || async move { api::get_mqtt(mqtt) }).put(
+                move |Json(update): Json<api::MqttUpdate, 0>| async move {
+                    api::update_mqtt(mqtt, journal, update)
+                },
+            ),
+        )
+        .route(
+            "/api/system",
+            get(move || async move { api::get_system(provisioning) }),
+        )
+        .route(
+            "/api/storage",
+            get(move || async move { api::get_storage(journal) }),
+        )
+        .route(
+            "/api/ota",
+            get(move || async move { api::get_ota_status(ota) }),
+        )
+        .route(
+            "/api/ota/pull",
+            post(move |Json(body): Json<api::UrlBody, 0>| async move {
+                api::pull_firmware(ota, pull, body)
+            }),
+        )
+        .route(
+            "/api/ota/manifest",
+            get(move || async move { api::get_manifest(manifest) }).put(
+                move |Json(body): Json<api::UrlBody, 0>| async move {
+                    api::update_manifest(manifest, journal, body)
+                },
+            ),
+        )
+        .route(
+            "/api/ota/health",
+            get(move || async move { api::get_health_check(health) }).put(
+                move |Json(update): Json<HealthCheckConfig, 0>| async move {
+                    api::update_health_check(health, journal, update)
+                },
+            ),
+        )
+        .route(
+            "/api/ota/history",
+            get(move || async move { api::get_boot_history(journal) }),
+        )
+        .route(
+            "/api/auth",
+            get(move || async move { api::get_auth(auth) }).put(
+                move |Json(update): Json<api::AuthUpdate, 0>| async move {
+                    api::update_auth(auth, journal, rng, update)
+                },
+            ),
+        )
+        // Clients from before the brightness and temperature format connect to `/ws`
+        .route(
+            "/ws",
+            get(move |update: WebSocketUpgrade| {
+                update.on_upgrade(ColorHandler {
+                    color: data,
+                    curve,
+                    save,
+                    format: PacketFormat::Legacy,
+                    position,
+                })
+            }),
+        )
+        .route(
+            "/ws/v2",
+            get(move |update: WebSocketUpgrade| {
+                update.on_upgrade(ColorHandler {
+                    color: data,
+                    curve,
+                    save,
+                    format: PacketFormat::State,
+                    position,
+                })
+            }),
+        )
+        .layer(ServedLayer { served })
+        .layer(AuthLayer { auth })
+}
+
+/// The access point only serves the provisioning page, the lamp is controlled from its network
+#[define_opaque(PortalRouter)]
+pub fn make_portal_app(ctx: AppContext) -> Router<PortalRouter> {
+    provisioning_routes(ctx)
+        .route("/", get(|| async { captive_redirect() }))
+        .layer(ServedLayer { served: ctx.served })
+        .layer(AuthLayer { auth: ctx.auth })
+}
+
+/// Routes for setting up the wifi, served on both networks
+fn provisioning_routes(ctx: AppContext) -> Router<impl PathRouter> {
+    let AppContext {
+        journal,
+        provisioning,
+        ..
+    } = ctx;
+
+    picoserve::Router::new()
         .route(
             "/style.css",
             get_service(response::File::css(include_str!("../resources/style.css"))),
         )
         .route(
-            "/ws",
-            get(move |update: WebSocketUpgrade| update.on_upgrade(ColorHandler { color: data })),
+            "/provisioning",
+            get_service(response::File::html(include_str!(
+                "../resources/provisioning.html"
+            ))),
         )
+        // Connectivity checks of Android, Apple and Windows, redirecting them opens the portal
+        .route("/generate_204", get(|| async { captive_redirect() }))
+        .route("/gen_204", get(|| async { captive_redirect() }))
+        .route("/hotspot-detect.html", get(|| async { captive_redirect() }))
+        .route("/connecttest.txt", get(|| async { captive_redirect() }))
+        .route("/ncsi.txt", get(|| async { captive_redirect() }))
+        .route(
+            "/api/wifi/scan",
+            get(move || async move { api::get_scan(provisioning) }),
+        )
+        .route(
+            "/api/wifi/networks",
+            get(move || async move { api::get_wifi_networks(provisioning) })
+                .post(
+                    move |Json(credentials): Json<WifiCredentials, 128>| async move {
+                        api::add_wifi_network(journal, provisioning, credentials)
+                    },
+                )
+                .delete(
+                    move |Json(removal): Json<api::NetworkRemoval, 64>| async move {
+                        api::remove_wifi_network(journal, provisioning, removal)
+                    },
+                ),
+        )
+}
+
+fn captive_redirect() -> response::Redirect {
+    response::Redirect::to(PROVISIONING_URL)
+}
+
+/// Packet layout a websocket client speaks, see `resources/data-format.md`
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum PacketFormat {
+    /// `[SAVE, BRIGHTNESS, TEMPERATURE]`
+    State,
+    /// `[SAVE, COLD, WARM, X, Y]`
+    Channels,
+    /// `[COLD, WARM, X, Y]` of the first clients
+    Legacy,
 }
 
+/// Disk position last sent by a client in a channel format, which those clients expect back
+pub type DiskPosition = Mutex<NoopRawMutex, Cell<[u16; 2]>>;
+
 pub struct ColorHandler {
     color: &'static ValueSynchronizer<MAX_LISTENERS, NoopRawMutex, LightState>,
+    curve: &'static SharedDimmingCurve,
+    save: &'static SaveSignal,
+    /// Format sent until the client sends a packet, then its format is used
+    format: PacketFormat,
+    position: &'static DiskPosition,
+}
+
+/// A decoded packet
+struct ColorPacket {
+    state: LightState,
+    save: bool,
+    format: PacketFormat,
+    position: Option<[u16; 2]>,
+}
+
+impl ColorHandler {
+    fn encode_packet(
+        &self,
+        state: LightState,
+        format: PacketFormat,
+    ) -> heapless::Vec<u8, CHANNEL_PACKET_LEN> {
+        let mut packet = heapless::Vec::new();
+        if format != PacketFormat::Legacy {
+            packet.extend_from_slice(&[0, 0]).unwrap();
+        }
+        if format == PacketFormat::State {
+            packet.extend_from_slice(&state.into_bytes()).unwrap();
+            return packet;
+        }
+        let (cold, warm) = state.channels(&self.curve.read_clone());
+        let [x, y] = self.position.lock(Cell::get);
+        for value in [cold, warm, x, y] {
+            packet.extend_from_slice(&value.to_le_bytes()).unwrap();
+        }
+        packet
+    }
+
+    /// Parses a received packet, the format is recognized by its length
+    fn decode_packet(bytes: &[u8], curve: &DimmingCurve) -> Option<ColorPacket> {
+        let save = || u16::from_le_bytes([bytes[0], bytes[1]]) == 1;
+        let position = |channels: &[u8]| {
+            [
+                u16::from_le_bytes([channels[4], channels[5]]),
+                u16::from_le_bytes([channels[6], channels[7]]),
+            ]
+        };
+        match bytes.len() {
+            PACKET_LEN => Some(ColorPacket {
+                state: LightState::from_bytes(bytes[2..].try_into().ok()?),
+                save: save(),
+                format: PacketFormat::State,
+                position: None,
+            }),
+            CHANNEL_PACKET_LEN => Some(ColorPacket {
+                state: LightState::from_channel_bytes(bytes[2..].try_into().ok()?, curve),
+                save: save(),
+                format: PacketFormat::Channels,
+                position: Some(position(&bytes[2..])),
+            }),
+            // Legacy clients send the bare channels and expect them to be saved
+            CHANNEL_STATE_LEN => Some(ColorPacket {
+                state: LightState::from_channel_bytes(bytes.try_into().ok()?, curve),
+                save: true,
+                format: PacketFormat::Legacy,
+                position: Some(position(bytes)),
+            }),
+            _ => None,
+        }
+    }
 }
 
 impl WebSocketCallback for ColorHandler {
@@ -54,12 +411,13 @@ impl WebSocketCallback for ColorHandler {
         mut rx: SocketRx<R>,
         mut tx: SocketTx<W>,
     ) -> Result<(), W::Error> {
-        let mut message_buffer = [0u8; LIGHT_STATE_LEN];
+        let mut message_buffer = [0u8; CHANNEL_PACKET_LEN];
         let mut watcher = self.color.watch();
+        let mut format = self.format;
 
         // Send initial message
         log::info!("Websocket opened, sending initial message");
-        tx.send_binary(&self.color.read_clone().into_bytes())
+        tx.send_binary(&self.encode_packet(self.color.read_clone(), format))
             .await?;
 
         loop {
@@ -72,42 +430,28 @@ impl WebSocketCallback for ColorHandler {
                             return Ok(());
                         }
                     };
-                    let Ok(bytes): Result<&[u8; LIGHT_STATE_LEN], _> = bytes.try_into() else {
+                    let Some(packet) = Self::decode_packet(bytes, &self.curve.read_clone()) else {
                         log::info!("Received invalid WS bytes: {bytes:?}");
                         return Ok(());
                     };
-                    let message = LightState::from_bytes(bytes);
-                    self.color.write(message).await;
+                    format = packet.format;
+                    if let Some(position) = packet.position {
+                        self.position.lock(|p| p.set(position));
+                    }
+                    self.color.write(packet.state).await;
                     watcher.skip().await;
+                    if packet.save {
+                        self.save.signal(());
+                    }
                 }
                 Either::Second(message) => {
-                    tx.send_binary(&message.into_bytes()).await?;
+                    tx.send_binary(&self.encode_packet(message, format)).await?;
                 }
             }
         }
     }
 }
 
-struct OtaHandler;
-
-impl RequestHandlerService<()> for OtaHandler {
-    async fn call_request_handler_service<R: Read, W: ResponseWriter<Error = R::Error>>(
-        &self,
-        _state: &(),
-        _path_parameters: (),
-        mut request: Request<'_, R>,
-        _response_writer: W,
-    ) -> Result<ResponseSent, W::Error> {
-        let reader = request.body_connection.body().reader();
-        log::info!("Starting OTA update...");
-        ota_begin(&mut FlashStorage::new(), reader, |_| {})
-            .await
-            .unwrap();
-        log::info!("OTA update finished, resetting...");
-        software_reset();
-    }
-}
-
 struct LogHandler {
     logger: &'static RingBufferLogger,
 }
@@ -1,35 +1,142 @@
+use crate::captive_portal::{PORTAL_ADDRESS, PORTAL_PREFIX_LEN};
+use crate::device_name::{DeviceName, SharedDeviceName};
 use crate::make_static;
+use crate::network_config::{Ipv4Settings, SharedIpv4Settings};
+use crate::slaac::{link_local_config, setup_slaac};
+use crate::wifi_credentials::{WifiCredentials, WifiNetworks, MAX_NETWORKS, MAX_SSID_LEN};
+use core::cell::{Cell, RefCell};
+use core::fmt::Write;
 use embassy_executor::Spawner;
-use embassy_net::{Config, StackResources};
+use embassy_futures::select::{select, select3, Either, Either3};
+use embassy_net::{
+    Config, ConfigV4, ConfigV6, DhcpConfig, Ipv4Cidr, StackResources, StaticConfigV4,
+};
 use embassy_net::{Runner, Stack};
-use embassy_time::{Duration, Timer};
-use esp_hal::peripherals::{RNG, SYSTIMER, WIFI};
+use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::signal::Signal;
+use embassy_time::{Duration, Instant, Timer};
+use esp_hal::peripherals::{SYSTIMER, WIFI};
 use esp_hal::rng::Rng;
 use esp_hal::timer::systimer::SystemTimer;
 use esp_wifi::wifi::{
-    ClientConfiguration, Configuration, WifiController, WifiDevice, WifiEvent, WifiState,
+    AccessPointConfiguration, AuthMethod, ClientConfiguration, Configuration, ScanConfig,
+    WifiController, WifiDevice, WifiEvent, WifiState,
 };
 use esp_wifi::{init, EspWifiController};
+use heapless::String;
+use serde::Serialize;
 
-const SSID: &str = "Jonathan's Tennisnet";
-const PASSWORD: &str = "nahtanoj";
 const MAX_SOCKETS: usize = 16;
+const AP_MAX_SOCKETS: usize = 8;
+pub const MAX_SCAN_RESULTS: usize = 16;
+
+/// The access point is started when no connection could be made for this long
+const PROVISIONING_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+/// Time the access point stays up after connecting, so the portal can show the result
+const PORTAL_LINGER: Duration = Duration::from_secs(60);
+
+pub struct WifiStacks {
+    /// Stack of the connection to the configured network
+    pub sta: Stack<'static>,
+    /// Stack of the provisioning access point
+    pub ap: Stack<'static>,
+    pub provisioning: &'static WifiProvisioning,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct ScanResult {
+    pub ssid: String<MAX_SSID_LEN>,
+    pub rssi: i8,
+    pub secure: bool,
+}
+
+/// Communication between the http endpoints and the connection task
+pub struct WifiProvisioning {
+    wake: Signal<NoopRawMutex, ()>,
+    networks: Mutex<NoopRawMutex, RefCell<WifiNetworks>>,
+    networks_changed: Mutex<NoopRawMutex, Cell<bool>>,
+    scan_requested: Mutex<NoopRawMutex, Cell<bool>>,
+    scan_results: Mutex<NoopRawMutex, RefCell<heapless::Vec<ScanResult, MAX_SCAN_RESULTS>>>,
+    /// Signal strength of the current connection, refreshed while connected
+    rssi: Mutex<NoopRawMutex, Cell<Option<i32>>>,
+}
 
-pub async fn setup_wifi(
+impl WifiProvisioning {
+    fn new(networks: WifiNetworks) -> Self {
+        Self {
+            wake: Signal::new(),
+            networks: Mutex::new(RefCell::new(networks)),
+            networks_changed: Mutex::new(Cell::new(false)),
+            scan_requested: Mutex::new(Cell::new(false)),
+            scan_results: Mutex::new(RefCell::new(heapless::Vec::new())),
+            rssi: Mutex::new(Cell::new(None)),
+        }
+    }
+
+    pub fn networks(&self) -> WifiNetworks {
+        self.networks.lock(|n| n.borrow().clone())
+    }
+
+    /// Changes the known networks, if they changed the connection task reconsiders its connection
+    pub fn update_networks<R>(&self, f: impl FnOnce(&mut WifiNetworks) -> R) -> R {
+        let (result, changed) = self.networks.lock(|n| {
+            let mut networks = n.borrow_mut();
+            let before = networks.clone();
+            let result = f(&mut networks);
+            (result, *networks != before)
+        });
+        if changed {
+            self.networks_changed.lock(|c| c.set(true));
+            self.wake.signal(());
+        }
+        result
+    }
+
+    /// Requests a new scan, the results of which are available later through `scan_results`
+    pub fn request_scan(&self) {
+        self.scan_requested.lock(|s| s.set(true));
+        self.wake.signal(());
+    }
+
+    pub fn scan_results(&self) -> heapless::Vec<ScanResult, MAX_SCAN_RESULTS> {
+        self.scan_results.lock(|r| r.borrow().clone())
+    }
+
+    pub fn rssi(&self) -> Option<i32> {
+        self.rssi.lock(|r| r.get())
+    }
+}
+
+pub fn setup_wifi(
     systimer: SYSTIMER<'static>,
-    rng: RNG<'static>,
+    mut rng: Rng,
     wifi: WIFI<'static>,
     spawner: Spawner,
-) -> Stack<'static> {
+    networks: WifiNetworks,
+    name: &'static SharedDeviceName,
+    ipv4: &'static SharedIpv4Settings,
+) -> WifiStacks {
     let timer = SystemTimer::new(systimer).alarm0;
-    let mut rng = Rng::new(rng);
     let init: &'static EspWifiController<'static> =
         make_static!(EspWifiController<'static>, init(timer, rng).unwrap());
 
     let (controller, wifi_interface) = esp_wifi::wifi::new(init, wifi).unwrap();
     let sta_interface = wifi_interface.sta;
+    let ap_interface = wifi_interface.ap;
+
+    let sta_mac = sta_interface.mac_address();
+    let mac = ap_interface.mac_address();
+    let mut ap_ssid = String::new();
+    write!(ap_ssid, "Lightbringer-{:02X}{:02X}", mac[4], mac[5]).unwrap();
 
-    let config = Config::dhcpv4(Default::default());
+    let mut config = match ipv4.read_clone() {
+        Ipv4Settings::Dhcp => Config::dhcpv4(dhcp_config(&name.read_clone())),
+        Ipv4Settings::Static(config) => Config::ipv4_static(config),
+    };
+    // The global IPv6 address is configured from router advertisements by `slaac_task`,
+    // until then the lamp is reachable on its link-local address
+    config.ipv6 = ConfigV6::Static(link_local_config(sta_mac));
     // Init network stack
     let (stack, runner): (Stack<'static>, Runner<_>) = embassy_net::new(
         sta_interface,
@@ -40,9 +147,41 @@ pub async fn setup_wifi(
         ),
         (rng.random() as u64) << 32 | rng.random() as u64,
     );
-    spawner.spawn(connect_task(controller)).ok();
+
+    let ap_config = Config::ipv4_static(StaticConfigV4 {
+        address: Ipv4Cidr::new(PORTAL_ADDRESS, PORTAL_PREFIX_LEN),
+        gateway: Some(PORTAL_ADDRESS),
+        dns_servers: Default::default(),
+    });
+    let (ap_stack, ap_runner): (Stack<'static>, Runner<_>) = embassy_net::new(
+        ap_interface,
+        ap_config,
+        make_static!(
+            StackResources<AP_MAX_SOCKETS>,
+            StackResources::<AP_MAX_SOCKETS>::new()
+        ),
+        (rng.random() as u64) << 32 | rng.random() as u64,
+    );
+
+    let provisioning = make_static!(WifiProvisioning, WifiProvisioning::new(networks));
+    spawner
+        .spawn(connect_task(controller, provisioning, ap_ssid))
+        .ok();
     spawner.spawn(net_task(runner)).ok();
+    spawner.spawn(net_task(ap_runner)).ok();
+    spawner.spawn(ipv4_config_task(stack, name, ipv4)).ok();
+    spawner.spawn(address_log_task(stack)).ok();
+    setup_slaac(stack, sta_mac, spawner);
+
+    WifiStacks {
+        sta: stack,
+        ap: ap_stack,
+        provisioning,
+    }
+}
 
+/// Waits until the lamp has an IPv4 or a global IPv6 address on the configured network
+pub async fn wait_for_ip(stack: Stack<'static>) {
     log::info!("Waiting for network stack...");
     loop {
         if stack.is_link_up() {
@@ -51,57 +190,280 @@ pub async fn setup_wifi(
         Timer::after(Duration::from_millis(500)).await;
     }
 
+    // The addresses themselves are logged by `address_log_task`
     log::info!("Waiting to get IP address...");
     loop {
-        if let Some(config) = stack.config_v4() {
-            log::info!("Got IP: {}", config.address);
+        let has_global_v6 = stack
+            .config_v6()
+            .is_some_and(|config| !config.address.address().is_unicast_link_local());
+        if stack.config_v4().is_some() || has_global_v6 {
             break;
         }
         Timer::after(Duration::from_millis(500)).await;
     }
+}
 
-    stack
+fn dhcp_config(name: &DeviceName) -> DhcpConfig {
+    let mut config = DhcpConfig::default();
+    config.hostname = Some(name.clone());
+    config
 }
 
-/// Task with the goal to connect to the wifi network when possible
+/// Logs the addresses of the lamp whenever they change
 #[embassy_executor::task]
-async fn connect_task(mut controller: WifiController<'static>) {
+async fn address_log_task(stack: Stack<'static>) -> ! {
+    let mut last_v4 = None;
+    let mut last_v6 = None;
+    loop {
+        let v4 = stack.config_v4().map(|config| config.address);
+        if v4 != last_v4 {
+            match v4 {
+                Some(address) => log::info!("IPv4 address is {address}"),
+                None => log::info!("Lost IPv4 address"),
+            }
+            last_v4 = v4;
+        }
+        let v6 = stack.config_v6().map(|config| config.address);
+        if v6 != last_v6 {
+            match v6 {
+                Some(address) => log::info!("IPv6 address is {address}"),
+                None => log::info!("Lost IPv6 address"),
+            }
+            last_v6 = v6;
+        }
+        Timer::after_secs(5).await;
+    }
+}
+
+/// Reconfigures the stack when the IPv4 settings change,
+/// or restarts DHCP when the device name changes so the router learns the new hostname
+#[embassy_executor::task]
+async fn ipv4_config_task(
+    stack: Stack<'static>,
+    name: &'static SharedDeviceName,
+    ipv4: &'static SharedIpv4Settings,
+) -> ! {
+    let mut name_watcher = name.watch();
+    let mut ipv4_watcher = ipv4.watch();
+    loop {
+        let settings = match select(name_watcher.read(), ipv4_watcher.read()).await {
+            Either::First(_) => ipv4.read_clone(),
+            Either::Second(settings) => settings,
+        };
+        let config = match settings {
+            Ipv4Settings::Dhcp => ConfigV4::Dhcp(dhcp_config(&name.read_clone())),
+            Ipv4Settings::Static(config) => ConfigV4::Static(config),
+        };
+        log::info!("Applying IPv4 config {config:?}");
+        stack.set_config_v4(config);
+    }
+}
+
+fn configuration(
+    credentials: Option<&WifiCredentials>,
+    portal: bool,
+    ap_ssid: &str,
+) -> Configuration {
+    let client = match credentials {
+        Some(credentials) => ClientConfiguration {
+            ssid: credentials.ssid.as_str().into(),
+            password: credentials.password.as_str().into(),
+            auth_method: if credentials.password.is_empty() {
+                AuthMethod::None
+            } else {
+                AuthMethod::WPA2Personal
+            },
+            ..Default::default()
+        },
+        None => ClientConfiguration::default(),
+    };
+    if !portal {
+        return Configuration::Client(client);
+    }
+
+    let access_point = AccessPointConfiguration {
+        ssid: ap_ssid.into(),
+        auth_method: AuthMethod::None,
+        ..Default::default()
+    };
+    Configuration::Mixed(client, access_point)
+}
+
+/// Scans for networks, publishing the results to the provisioning portal
+async fn scan(
+    controller: &mut WifiController<'static>,
+    provisioning: &WifiProvisioning,
+) -> heapless::Vec<ScanResult, MAX_SCAN_RESULTS> {
+    log::info!("Scanning for wifi networks...");
+    let access_points = match controller
+        .scan_with_config_async(ScanConfig::default())
+        .await
+    {
+        Ok(access_points) => access_points,
+        Err(e) => {
+            log::info!("Failed to scan for wifi networks: {e:?}");
+            return heapless::Vec::new();
+        }
+    };
+
+    let results: heapless::Vec<_, MAX_SCAN_RESULTS> = access_points
+        .iter()
+        .filter_map(|ap| {
+            Some(ScanResult {
+                ssid: ap.ssid.as_str().try_into().ok()?,
+                rssi: ap.signal_strength,
+                secure: !matches!(ap.auth_method, None | Some(AuthMethod::None)),
+            })
+        })
+        .take(MAX_SCAN_RESULTS)
+        .collect();
+    provisioning
+        .scan_results
+        .lock(|r| *r.borrow_mut() = results.clone());
+    results
+}
+
+/// Orders the known networks by the order in which they should be tried:
+/// visible networks by priority and then signal strength, followed by possibly hidden ones
+fn candidates<'a>(
+    networks: &'a WifiNetworks,
+    found: &[ScanResult],
+) -> heapless::Vec<&'a WifiCredentials, MAX_NETWORKS> {
+    let mut candidates: heapless::Vec<_, MAX_NETWORKS> = networks
+        .iter()
+        .map(|network| {
+            let rssi = found
+                .iter()
+                .filter(|r| r.ssid == network.ssid)
+                .map(|r| r.rssi)
+                .max();
+            (network, rssi)
+        })
+        .collect();
+    candidates.sort_unstable_by_key(|(network, rssi)| {
+        core::cmp::Reverse((rssi.is_some(), network.priority, *rssi))
+    });
+    candidates.into_iter().map(|(network, _)| network).collect()
+}
+
+/// Task with the goal to connect to the best known wifi network when possible
+#[embassy_executor::task]
+async fn connect_task(
+    mut controller: WifiController<'static>,
+    provisioning: &'static WifiProvisioning,
+    ap_ssid: String<MAX_SSID_LEN>,
+) {
     log::info!("Start connection task...");
 
+    let mut networks = provisioning.networks();
+    let mut current: Option<WifiCredentials> = None;
+    let mut portal = networks.is_empty();
+    let mut last_connected = Instant::now();
+    let mut connected_since = None;
+    controller
+        .set_configuration(&configuration(None, portal, &ap_ssid))
+        .unwrap();
+
+    // On first setup, wait 2 seconds in order to avoid bootlooping bug
+    Timer::after_secs(2).await;
+    log::info!("Starting wifi controller...");
+    controller.start().unwrap();
+
     loop {
-        if let WifiState::StaConnected = esp_wifi::wifi::wifi_state() {
-            // wait until we're no longer connected
-            controller.wait_for_event(WifiEvent::StaDisconnected).await;
-            log::info!("Disconnected from wifi, waiting 5 seconds before reconnecting...");
-            Timer::after(Duration::from_millis(5000)).await
+        if provisioning.networks_changed.lock(|c| c.replace(false)) {
+            networks = provisioning.networks();
+            // Reconnect when the current network was changed or removed
+            if current.as_ref().is_some_and(|c| !networks.contains(c)) {
+                log::info!("Current wifi network was changed, disconnecting...");
+                controller.disconnect_async().await.ok();
+                current = None;
+            }
+        }
+        if provisioning.scan_requested.lock(|s| s.replace(false)) {
+            scan(&mut controller, provisioning).await;
+        }
+
+        let connected = matches!(esp_wifi::wifi::wifi_state(), WifiState::StaConnected);
+        let now = Instant::now();
+        let want_portal = if connected {
+            last_connected = now;
+            let since = *connected_since.get_or_insert(now);
+            portal && now - since < PORTAL_LINGER
+        } else {
+            connected_since = None;
+            networks.is_empty() || now - last_connected > PROVISIONING_TIMEOUT
+        };
+        if want_portal != portal {
+            log::info!(
+                "{} provisioning access point {ap_ssid}",
+                if want_portal { "Starting" } else { "Stopping" }
+            );
+            portal = want_portal;
+            if let Err(e) =
+                controller.set_configuration(&configuration(current.as_ref(), portal, &ap_ssid))
+            {
+                log::info!("Failed to configure wifi: {e:?}");
+            }
         }
-        if !matches!(controller.is_started(), Ok(true)) {
-            let client_config = Configuration::Client(ClientConfiguration {
-                ssid: SSID.into(),
-                password: PASSWORD.into(),
-                ..Default::default()
-            });
-            controller.set_configuration(&client_config).unwrap();
-
-            // On first setup, wait 2 seconds in order to avoid bootlooping bug
-            Timer::after_secs(2).await;
-            log::info!("Starting wifi controller...");
-            controller.start().unwrap();
+
+        let rssi = if connected {
+            controller.rssi().ok()
+        } else {
+            None
+        };
+        provisioning.rssi.lock(|r| r.set(rssi));
+        if connected {
+            // wait until we're no longer connected, checking the portal and requests regularly
+            let disconnected = controller.wait_for_event(WifiEvent::StaDisconnected);
+            let event = select3(
+                disconnected,
+                provisioning.wake.wait(),
+                Timer::after_secs(10),
+            )
+            .await;
+            if let Either3::First(()) = event {
+                log::info!("Disconnected from wifi, looking for known networks...");
+                current = None;
+            }
+            continue;
+        }
+
+        if networks.is_empty() {
+            provisioning.wake.wait().await;
+            continue;
         }
-        log::info!("Trying to connect to wifi network...");
 
-        match controller.connect_async().await {
-            Ok(_) => log::info!("Wifi connected!"),
-            Err(e) => {
-                log::info!("Failed to connect to wifi: {e:?}");
-                Timer::after(Duration::from_millis(5000)).await
+        let found = scan(&mut controller, provisioning).await;
+        for network in candidates(&networks, &found) {
+            log::info!("Trying to connect to wifi network {}...", network.ssid);
+            if let Err(e) =
+                controller.set_configuration(&configuration(Some(network), portal, &ap_ssid))
+            {
+                log::info!("Failed to configure wifi: {e:?}");
+                continue;
             }
+            match controller.connect_async().await {
+                Ok(_) => {
+                    log::info!("Wifi connected!");
+                    current = Some(network.clone());
+                    break;
+                }
+                Err(e) => log::info!("Failed to connect to wifi: {e:?}"),
+            }
+        }
+
+        if current.is_none() {
+            select(
+                Timer::after(Duration::from_millis(5000)),
+                provisioning.wake.wait(),
+            )
+            .await;
         }
     }
 }
 
 /// Task that runs the network stack
-#[embassy_executor::task]
+#[embassy_executor::task(pool_size = 2)]
 async fn net_task(mut runner: Runner<'static, WifiDevice<'static>>) {
     runner.run().await
 }
@@ -0,0 +1,126 @@
+use crate::journal::{read_str, store_record, write_str, Journal, RecordKind, SharedJournal};
+use heapless::String;
+use serde::Deserialize;
+
+pub const MAX_SSID_LEN: usize = 32;
+pub const MAX_PASSWORD_LEN: usize = 64;
+pub const MAX_NETWORKS: usize = 5;
+const WIFI_CREDENTIALS_LEN: usize = 3 + MAX_SSID_LEN + MAX_PASSWORD_LEN;
+const WIFI_NETWORKS_LEN: usize = 1 + MAX_NETWORKS * WIFI_CREDENTIALS_LEN;
+const RECORD_VERSION: u8 = 2;
+
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+pub struct WifiCredentials {
+    pub ssid: String<MAX_SSID_LEN>,
+    pub password: String<MAX_PASSWORD_LEN>,
+    /// Among the visible networks, the one with the highest priority is preferred
+    #[serde(default)]
+    pub priority: u8,
+}
+
+impl WifiCredentials {
+    /// Reads `[SSID_LEN, SSID.., PASSWORD_LEN, PASSWORD..]`, returning the remaining bytes
+    fn read_from(bytes: &[u8], priority: u8) -> Option<(Self, &[u8])> {
+        let (ssid, rest) = read_str(bytes)?;
+        let (password, rest) = read_str(rest)?;
+        let credentials = Self {
+            ssid: ssid.try_into().ok()?,
+            password: password.try_into().ok()?,
+            priority,
+        };
+        Some((credentials, rest))
+    }
+
+    fn write_to(&self, bytes: &mut heapless::Vec<u8, WIFI_NETWORKS_LEN>) {
+        bytes.push(self.priority).unwrap();
+        write_str(bytes, &self.ssid);
+        write_str(bytes, &self.password);
+    }
+}
+
+/// The networks the lamp connects to
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct WifiNetworks(heapless::Vec<WifiCredentials, MAX_NETWORKS>);
+
+impl WifiNetworks {
+    /// Reads `[COUNT, (PRIORITY, CREDENTIALS)..]`
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let (count, mut rest) = bytes.split_first()?;
+        let mut networks = heapless::Vec::new();
+        for _ in 0..*count {
+            let (priority, bytes) = rest.split_first()?;
+            let (credentials, bytes) = WifiCredentials::read_from(bytes, *priority)?;
+            networks.push(credentials).ok()?;
+            rest = bytes;
+        }
+        Some(Self(networks))
+    }
+
+    pub fn into_bytes(self) -> heapless::Vec<u8, WIFI_NETWORKS_LEN> {
+        let mut bytes = heapless::Vec::new();
+        bytes.push(self.0.len() as u8).unwrap();
+        for credentials in &self.0 {
+            credentials.write_to(&mut bytes);
+        }
+        bytes
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &WifiCredentials> {
+        self.0.iter()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Whether the network is still known with the same password, its priority aside
+    pub fn contains(&self, credentials: &WifiCredentials) -> bool {
+        self.0
+            .iter()
+            .any(|n| n.ssid == credentials.ssid && n.password == credentials.password)
+    }
+
+    /// Adds a network, replacing a network with the same SSID. Fails when the list is full.
+    pub fn add(&mut self, credentials: WifiCredentials) -> Result<(), WifiCredentials> {
+        match self.0.iter_mut().find(|n| n.ssid == credentials.ssid) {
+            Some(existing) => {
+                *existing = credentials;
+                Ok(())
+            }
+            None => self.0.push(credentials),
+        }
+    }
+
+    /// Removes the network with the given SSID, returning whether it existed
+    pub fn remove(&mut self, ssid: &str) -> bool {
+        let len = self.0.len();
+        self.0.retain(|n| n.ssid != ssid);
+        self.0.len() != len
+    }
+}
+
+pub fn read_wifi_networks(journal: &mut Journal) -> WifiNetworks {
+    let mut buffer = [0; WIFI_NETWORKS_LEN];
+    let networks = match journal.read_latest(RecordKind::WifiNetworks, &mut buffer) {
+        None => None,
+        Some((RECORD_VERSION, len)) => WifiNetworks::from_bytes(&buffer[..len]),
+        Some((version, _)) => {
+            log::warn!("Unknown wifi networks record version {version}");
+            None
+        }
+    };
+    networks.unwrap_or_default()
+}
+
+pub fn store_wifi_networks(journal: &SharedJournal, networks: WifiNetworks) -> bool {
+    let stored = store_record(
+        journal,
+        RecordKind::WifiNetworks,
+        RECORD_VERSION,
+        &networks.into_bytes(),
+    );
+    if stored {
+        log::info!("Wifi networks updated");
+    }
+    stored
+}